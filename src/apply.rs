@@ -0,0 +1,120 @@
+//! Replay the renames of a saved mapping
+
+use crate::fs_ext;
+use crate::fs_ext::FSExt;
+use crate::opts::ApplyOpts;
+use crate::overlay_fs;
+use crate::renames::Rename;
+use crate::signals;
+use crate::Result;
+
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Component;
+use std::path::Path;
+
+/// Perform `renames` in order on `fs`, with relative paths resolved from
+/// `root`. Each source must exist and each target must be free when it is
+/// renamed, without replacing a target created in the meantime. Renames are
+/// printed with `prefix` if given.
+fn rename_all<FS: FSExt>(
+    fs: &FS,
+    root: &Path,
+    renames: &[Rename],
+    prefix: Option<&str>,
+) -> Result<()> {
+    for (i, rename) in renames.iter().enumerate() {
+        // Stop between renames after SIGINT or SIGTERM
+        if let Some(signal) = signals::caught() {
+            return Err(format!(
+                "interrupted by {} after {} renames; {} renames were not done",
+                signals::name(signal),
+                i,
+                renames.len() - i
+            )
+            .into());
+        }
+
+        let from = root.join(&rename.from);
+        let to = root.join(&rename.to);
+        if let Err(err) = fs.symlink_metadata(&from) {
+            return Err(format!("cannot rename '{}': {}", rename.from.display(), err).into());
+        }
+        match fs.symlink_metadata(&to) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Ok(_) => {
+                return Err(format!(
+                    "cannot rename '{}' -> '{}': target already exists",
+                    rename.from.display(),
+                    rename.to.display()
+                )
+                .into())
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        if let Some(prefix) = prefix {
+            println!(
+                "{} '{}' -> '{}'",
+                prefix,
+                rename.from.display(),
+                rename.to.display()
+            );
+        }
+        // Mappings name paths like they were given on the command line, so
+        // symlinks in them are followed
+        fs_ext::rename_noreplace(fs, root, &rename.from, &rename.to, true)?;
+    }
+    Ok(())
+}
+
+/// Check that `path` from a mapping stays below the directory it is resolved
+/// from, so that a mapping made with absolute paths or `..` components cannot
+/// rename entries outside of `--root`
+fn check_below_root(path: &Path) -> Result<()> {
+    let escapes = path.components().any(|component| {
+        matches!(
+            component,
+            Component::Prefix(_) | Component::RootDir | Component::ParentDir
+        )
+    });
+    if escapes {
+        return Err(format!(
+            "cannot rename '{}' below --root: path is absolute or contains '..'",
+            path.display()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Apply the mapping in `opts`. All renames are first checked on an overlay
+/// over the real filesystem, so that nothing is renamed if any of them cannot
+/// be done. Renaming stops between renames after SIGINT or SIGTERM.
+pub fn apply(cwd: &Path, opts: &ApplyOpts) -> Result<()> {
+    let file = File::open(cwd.join(&opts.mapping))?;
+    let renames: Vec<Rename> = serde_json::from_reader(BufReader::new(file))
+        .map_err(|err| format!("invalid mapping '{}': {}", opts.mapping.display(), err))?;
+    let root = match &opts.root {
+        Some(root) => {
+            for rename in &renames {
+                check_below_root(&rename.from)?;
+                check_below_root(&rename.to)?;
+            }
+            cwd.join(root)
+        }
+        None => cwd.to_path_buf(),
+    };
+
+    if opts.dry_run {
+        return rename_all(
+            &overlay_fs::FS::default(),
+            &root,
+            &renames,
+            Some("would rename"),
+        );
+    }
+    rename_all(&overlay_fs::FS::default(), &root, &renames, None)?;
+    rename_all(&rsfs::disk::FS, &root, &renames, Some("rename"))
+}
@@ -0,0 +1,492 @@
+//! Sanitize the member paths of zip and tar archives without extracting them
+
+use crate::dir_index::DirNames;
+use crate::filename_parts::NamingRules;
+use crate::opts::ArchiveOpts;
+use crate::read_children_names;
+use crate::unixize_filename_str;
+use crate::EntryKind;
+use crate::Result;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use lzma_rust2::XzOptions;
+use lzma_rust2::XzReader;
+use lzma_rust2::XzWriter;
+
+/// Compression of a tar archive
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+/// Format of an archive, determined from its filename
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Tar(Compression),
+}
+
+impl Format {
+    /// Determine the format of the archive at `path` from its extension
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .map(OsStr::to_string_lossy)
+            .unwrap_or_default()
+            .to_lowercase();
+        let formats = [
+            (".zip", Self::Zip),
+            (".tar", Self::Tar(Compression::None)),
+            (".tar.gz", Self::Tar(Compression::Gzip)),
+            (".tgz", Self::Tar(Compression::Gzip)),
+            (".tar.xz", Self::Tar(Compression::Xz)),
+            (".txz", Self::Tar(Compression::Xz)),
+            (".tar.zst", Self::Tar(Compression::Zstd)),
+            (".tzst", Self::Tar(Compression::Zstd)),
+        ];
+        formats
+            .into_iter()
+            .find(|(ext, _)| name.ends_with(ext))
+            .map(|(_, format)| format)
+            .ok_or_else(|| format!("unknown archive format of '{}'", path.display()).into())
+    }
+}
+
+/// Wrap `reader` to decompress a tar archive compressed with `compression`
+pub fn decoder<'r, R: Read + 'r>(
+    reader: R,
+    compression: Compression,
+) -> Result<Box<dyn Read + 'r>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Xz => Box::new(XzReader::new(reader, true)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+    })
+}
+
+/// Writer compressing a tar archive, which must be finished to write the end
+/// of the compressed stream
+enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Xz(Box<XzWriter<W>>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(writer: W, compression: Compression) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None(writer),
+            Compression::Gzip => Self::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Xz => {
+                Self::Xz(Box::new(XzWriter::new(writer, XzOptions::with_preset(6))?))
+            }
+            Compression::Zstd => Self::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Self::None(writer) => Ok(writer),
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Xz(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Xz(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Xz(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Split the member path `path` into its components, without `.` and empty
+/// components. Absolute paths and paths with `..` components are rejected,
+/// since they could refer to files outside of the archive.
+fn components(path: &str) -> Result<Vec<&str>> {
+    if path.starts_with('/') || path.split('/').any(|component| component == "..") {
+        return Err(format!("unsafe path '{}' in archive", path).into());
+    }
+    Ok(path
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect())
+}
+
+/// Sanitized member paths of an archive, with collisions resolved per
+/// directory
+#[derive(Debug, Default)]
+pub struct PathMap {
+    /// Names in each directory, keyed by the original path of the directory
+    dirs: HashMap<String, DirNames>,
+
+    /// Sanitized path of each original path mapped so far, both without `.`
+    /// components and trailing slashes
+    paths: HashMap<String, String>,
+
+    /// Whether `dirs` was filled with the original names of all members
+    /// beforehand. Otherwise, it only holds the names mapped so far, so that
+    /// archives can be processed in a single pass.
+    seeded: bool,
+
+    /// Directory the archive is extracted to, if any. Names of entries that
+    /// already exist there are taken.
+    root: Option<PathBuf>,
+
+    /// How member names are split and rewritten. Archive commands take no
+    /// naming flags, so these are the defaults.
+    rules: NamingRules,
+}
+
+impl PathMap {
+    /// Create a map knowing all member paths up front, which keeps names that
+    /// are already sanitized from changing. The paths are mapped in sorted
+    /// order, like the entries of a directory are renamed.
+    pub fn with_paths<'p, I: IntoIterator<Item = &'p str>>(paths: I) -> Result<Self> {
+        let mut map = Self {
+            seeded: true,
+            ..Self::default()
+        };
+        let mut paths: Vec<&str> = paths.into_iter().collect();
+        for path in &paths {
+            let mut dir = String::new();
+            for component in components(path)? {
+                map.dirs
+                    .entry(dir.clone())
+                    .or_default()
+                    .insert(component.into());
+                dir = join(&dir, component);
+            }
+        }
+        paths.sort_unstable();
+        for path in paths {
+            map.map(path)?;
+        }
+        Ok(map)
+    }
+
+    /// Create a map for extracting to `root` in a single pass, which keeps
+    /// existing entries from being overwritten
+    pub fn in_dir(root: &Path) -> Self {
+        Self {
+            root: Some(root.to_path_buf()),
+            ..Self::default()
+        }
+    }
+
+    /// Sanitize each component of the member path `path`, keeping a leading
+    /// `./` and a trailing slash. Names whose sanitized form is taken in
+    /// their directory get a collision-resolving number.
+    pub fn map(&mut self, path: &str) -> Result<String> {
+        let mut old = String::new();
+        let mut new = String::new();
+        let components = components(path)?;
+        let count = components.len();
+        for (i, component) in components.into_iter().enumerate() {
+            let parent = old;
+            old = join(&parent, component);
+            if let Some(mapped) = self.paths.get(&old) {
+                new = mapped.clone();
+                continue;
+            }
+
+            let names = match self.dirs.entry(parent) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(match &self.root {
+                    Some(root) if root.join(&new).is_dir() => {
+                        DirNames::new(read_children_names(&rsfs::disk::FS, root, Path::new(&new))?)
+                    }
+                    _ => DirNames::default(),
+                }),
+            };
+            let kind = if i + 1 < count || path.ends_with('/') {
+                EntryKind::Dir
+            } else {
+                EntryKind::File
+            };
+            let sanitized = unixize_filename_str(component, kind, &self.rules);
+            // Names with nothing left after sanitizing are kept
+            let name = if sanitized.is_empty() {
+                component.to_string()
+            } else {
+                sanitized
+            };
+            let name = if self.seeded && name == component {
+                name
+            } else if self.seeded {
+                let name = names.resolve_collision(name, &self.rules);
+                names.rename(OsStr::new(component), OsStr::new(&name), &self.rules);
+                name
+            } else {
+                let name = names.resolve_collision(name, &self.rules);
+                names.insert(name.clone().into());
+                name
+            };
+            new = join(&new, &name);
+            self.paths.insert(old.clone(), new.clone());
+        }
+
+        let prefix = if path.starts_with("./") { "./" } else { "" };
+        let suffix = if path.ends_with('/') && !new.is_empty() {
+            "/"
+        } else {
+            ""
+        };
+        Ok(format!("{}{}{}", prefix, new, suffix))
+    }
+
+    /// Rewrite the target of the symlink at member path `path` to point at
+    /// the sanitized path of its target, if it was mapped and changed.
+    /// Absolute targets and targets outside of the archive are kept.
+    pub fn retarget(&self, path: &str, target: &str) -> Option<String> {
+        let mut old = components(path).ok()?;
+        old.pop();
+        let mut new_target = Vec::new();
+        let mut changed = false;
+
+        for component in target.split('/') {
+            match component {
+                "" if new_target.is_empty() => return None,
+                "" | "." => new_target.push(component.to_string()),
+                ".." => {
+                    old.pop()?;
+                    new_target.push(component.to_string());
+                }
+                name => {
+                    old.push(name);
+                    let new_name = self
+                        .paths
+                        .get(&old.join("/"))
+                        .and_then(|new| new.rsplit('/').next())
+                        .unwrap_or(name);
+                    changed |= new_name != name;
+                    new_target.push(new_name.to_string());
+                }
+            }
+        }
+
+        changed.then(|| new_target.join("/"))
+    }
+}
+
+/// Join the member path `dir` and `name`
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// Read a path from a tar header, replacing invalid UTF-8
+pub fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Copy the entries of `archive` to `builder`, with paths mapped through
+/// `map`. Hard link targets are mapped like paths, and symlink targets are
+/// rewritten to follow the members they point at.
+pub fn copy_tar<R: Read, W: Write>(
+    archive: &mut tar::Archive<R>,
+    builder: &mut tar::Builder<W>,
+    map: &mut PathMap,
+) -> Result<()> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let mut header = entry.header().clone();
+        let entry_type = header.entry_type();
+        if entry_type.is_pax_global_extensions() {
+            builder.append(&header, &mut entry)?;
+            continue;
+        }
+
+        let path = lossy(&entry.path_bytes());
+        let new_path = map.map(&path)?;
+        let target = entry.link_name_bytes().map(|target| lossy(&target));
+        match target {
+            Some(target) if entry_type.is_hard_link() => {
+                let new_target = map.map(&target)?;
+                builder.append_link(&mut header, new_path, new_target)?;
+            }
+            Some(target) if entry_type.is_symlink() => {
+                let new_target = map.retarget(&path, &target).unwrap_or(target);
+                builder.append_link(&mut header, new_path, new_target)?;
+            }
+            _ => builder.append_data(&mut header, new_path, &mut entry)?,
+        }
+    }
+    Ok(())
+}
+
+/// Print the renames of the member paths `paths` in `map`
+fn print_renames(paths: &[String], map: &mut PathMap) -> Result<()> {
+    for path in paths {
+        let new_path = map.map(path)?;
+        if *path != new_path {
+            println!("rename '{}' -> '{}'", path, new_path);
+        }
+    }
+    Ok(())
+}
+
+/// Copy the zip archive `input` to `output` with sanitized member paths.
+/// Members are copied without recompressing them.
+fn rewrite_zip(input: File, output: File) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(input))?;
+    let paths: Vec<String> = archive.file_names().map(String::from).collect();
+    let mut map = PathMap::with_paths(paths.iter().map(String::as_str))?;
+    print_renames(&paths, &mut map)?;
+
+    let mut writer = zip::ZipWriter::new(output);
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        let new_path = map.map(file.name())?;
+        writer.raw_copy_file_rename(file, new_path)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Copy the tar archive `input` to `output` with sanitized member paths,
+/// reading it twice to know all paths before mapping them
+fn rewrite_tar(input: &Path, compression: Compression, output: File) -> Result<()> {
+    let open = || -> Result<tar::Archive<Box<dyn Read>>> {
+        let reader = BufReader::new(File::open(input)?);
+        Ok(tar::Archive::new(decoder(reader, compression)?))
+    };
+
+    let mut paths = Vec::new();
+    for entry in open()?.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_pax_global_extensions() {
+            paths.push(lossy(&entry.path_bytes()));
+        }
+    }
+    let mut map = PathMap::with_paths(paths.iter().map(String::as_str))?;
+    print_renames(&paths, &mut map)?;
+
+    let mut builder = tar::Builder::new(Encoder::new(output, compression)?);
+    copy_tar(&mut open()?, &mut builder, &mut map)?;
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Write a copy of the archive in `opts` with every member path sanitized,
+/// in the same format. The output file must not exist already, and is
+/// removed if rewriting fails.
+pub fn rewrite(cwd: &Path, opts: &ArchiveOpts) -> Result<()> {
+    let format = Format::from_path(&opts.input)?;
+    let input = File::open(cwd.join(&opts.input))?;
+    let output_path = cwd.join(&opts.output);
+    let output = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&output_path)
+        .map_err(|err| format!("cannot create '{}': {}", opts.output.display(), err))?;
+
+    let result = match format {
+        Format::Zip => rewrite_zip(input, output),
+        Format::Tar(compression) => rewrite_tar(&cwd.join(&opts.input), compression, output),
+    };
+    if result.is_err() {
+        // Do not leave a partial archive behind
+        let _ = std::fs::remove_file(&output_path);
+    }
+    result
+}
+
+/// Copy a tar stream from standard input to standard output with sanitized
+/// member paths. Since paths are mapped as they are read, a name that is
+/// already sanitized is numbered if an earlier member was renamed to it, and
+/// symlinks pointing at later members are kept as they are.
+pub fn filter_tar() -> Result<()> {
+    let mut archive = tar::Archive::new(io::stdin().lock());
+    let mut builder = tar::Builder::new(BufWriter::new(io::stdout().lock()));
+    copy_tar(&mut archive, &mut builder, &mut PathMap::default())?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_map() {
+        let paths = [
+            "My Docs/",
+            "My Docs/a b.txt",
+            "My Docs/a_b.txt",
+            "My Docs/Sub Dir/",
+            "My Docs/Sub Dir/(1).txt",
+            "./ok.txt",
+        ];
+        let mut map = PathMap::with_paths(paths).unwrap();
+        assert_eq!(map.map("My Docs/").unwrap(), "My_Docs/");
+        assert_eq!(map.map("My Docs/a_b.txt").unwrap(), "My_Docs/a_b.txt");
+        assert_eq!(map.map("My Docs/a b.txt").unwrap(), "My_Docs/a_b_000.txt");
+        assert_eq!(
+            map.map("My Docs/Sub Dir/(1).txt").unwrap(),
+            "My_Docs/Sub_Dir/1.txt"
+        );
+        assert_eq!(map.map("./ok.txt").unwrap(), "./ok.txt");
+
+        assert!(map.map("../evil").is_err());
+        assert!(map.map("/etc/passwd").is_err());
+        assert!(map.map("a/../../evil").is_err());
+
+        assert_eq!(
+            map.retarget("My Docs/link", "Sub Dir/(1).txt"),
+            Some("Sub_Dir/1.txt".to_string())
+        );
+        assert_eq!(
+            map.retarget("My Docs/Sub Dir/link", "../a b.txt"),
+            Some("../a_b_000.txt".to_string())
+        );
+        assert_eq!(map.retarget("My Docs/link", "a_b.txt"), None);
+        assert_eq!(map.retarget("link", "../outside"), None);
+        assert_eq!(map.retarget("link", "/My Docs"), None);
+    }
+
+    #[test]
+    fn test_path_map_single_pass() {
+        let mut map = PathMap::default();
+        assert_eq!(map.map("a b").unwrap(), "a_b");
+        // Without knowing all paths, a sanitized name that comes later is
+        // renamed instead
+        assert_eq!(map.map("a_b").unwrap(), "a_b_000");
+        assert_eq!(map.map("a b").unwrap(), "a_b");
+    }
+}
@@ -0,0 +1,163 @@
+//! In-memory index of directory contents, used to resolve collisions without
+//! querying the filesystem for every candidate name
+
+use crate::filename_parts::FilenameParts;
+use crate::filename_parts::NamingRules;
+use crate::inc_filename_num;
+use crate::read_children_names;
+use crate::renames::normalize;
+use crate::Result;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+
+use rsfs::GenFS;
+
+/// Names of the entries in a single directory
+#[derive(Debug, Default)]
+pub struct DirNames {
+    names: HashSet<OsString>,
+
+    /// For each filename that collided, the name it was last resolved to.
+    /// All names between the two in the collision-resolving sequence are
+    /// taken, so the next search can continue from there.
+    hints: HashMap<String, String>,
+}
+
+impl DirNames {
+    pub fn new<I: IntoIterator<Item = OsString>>(names: I) -> Self {
+        Self {
+            names: names.into_iter().collect(),
+            hints: HashMap::new(),
+        }
+    }
+
+    /// Pick a name that is not taken, starting from `filename` and
+    /// incrementing its collision-resolving number, as split by `rules`,
+    /// until it is unique
+    pub fn resolve_collision(&mut self, filename: String, rules: &NamingRules) -> String {
+        if !self.names.contains(OsStr::new(&filename)) {
+            return filename;
+        }
+
+        let mut candidate = match self.hints.get(&filename) {
+            Some(hint) => hint.clone(),
+            None => filename.clone(),
+        };
+        while self.names.contains(OsStr::new(&candidate)) {
+            candidate = inc_filename_num(&candidate, rules);
+        }
+        self.hints.insert(filename, candidate.clone());
+        candidate
+    }
+
+    /// Returns `true` if an entry named `name` exists
+    pub fn contains(&self, name: &OsStr) -> bool {
+        self.names.contains(name)
+    }
+
+    /// Record that an entry named `name` was added
+    pub fn insert(&mut self, name: OsString) {
+        self.names.insert(name);
+    }
+
+    /// Record that the entry `from` was renamed to `to`
+    pub fn rename(&mut self, from: &OsStr, to: &OsStr, rules: &NamingRules) {
+        self.remove(from, rules);
+        self.names.insert(to.to_os_string());
+    }
+
+    /// Record that the entry `name` was removed. Hints that might skip over
+    /// the freed name are dropped.
+    pub fn remove(&mut self, name: &OsStr, rules: &NamingRules) {
+        if !self.names.remove(name) {
+            return;
+        }
+        if let Some(name) = name.to_str() {
+            let removed = FilenameParts::from_filename(name, rules);
+            self.hints.retain(|filename, _| {
+                let parts = FilenameParts::from_filename(filename, rules);
+                !(removed.stem.starts_with(&parts.stem) && removed.ext == parts.ext)
+            });
+        }
+    }
+}
+
+/// Cache of the names in directories, keyed by their normalized absolute paths
+#[derive(Debug, Default)]
+pub struct DirIndex {
+    dirs: HashMap<PathBuf, DirNames>,
+}
+
+impl DirIndex {
+    /// Get the names in `dir`, reading them from `fs` if they are not cached
+    pub fn get<FS: GenFS>(&mut self, fs: &FS, cwd: &Path, dir: &Path) -> Result<&mut DirNames> {
+        match self.dirs.entry(normalize(cwd, dir)) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let names = read_children_names(fs, cwd, dir)?;
+                Ok(entry.insert(DirNames::new(names)))
+            }
+        }
+    }
+
+    /// Cache `names` as the names in `dir`, replacing any cached ones
+    pub fn insert(&mut self, cwd: &Path, dir: &Path, names: DirNames) {
+        self.dirs.insert(normalize(cwd, dir), names);
+    }
+
+    /// Stop caching the names in `dir`, after it is done being processed
+    pub fn forget(&mut self, cwd: &Path, dir: &Path) {
+        self.dirs.remove(&normalize(cwd, dir));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_collision() {
+        let rules = NamingRules::default();
+        let mut names = DirNames::new(["a.txt", "a_000.txt", "a_001.txt", "b"].map(OsString::from));
+
+        let mut resolve = |filename: &str| {
+            let resolved = names.resolve_collision(filename.to_string(), &rules);
+            names.rename(OsStr::new("unused"), OsStr::new(&resolved), &rules);
+            resolved
+        };
+        assert_eq!(resolve("a.txt"), "a_002.txt");
+        assert_eq!(resolve("a.txt"), "a_003.txt");
+        assert_eq!(resolve("b"), "b_000");
+        assert_eq!(resolve("c"), "c");
+        assert_eq!(resolve("d.tar.gz"), "d.tar.gz");
+        assert_eq!(resolve("d.tar.gz"), "d_000.tar.gz");
+
+        // Freed names are reused
+        names.remove(OsStr::new("a_000.txt"), &rules);
+        assert_eq!(
+            names.resolve_collision("a.txt".to_string(), &rules),
+            "a_000.txt"
+        );
+    }
+
+    #[test]
+    fn test_many_collisions() {
+        let rules = NamingRules::default();
+        let mut names = DirNames::new([OsString::from("IMG.jpg")]);
+        let mut resolved = HashSet::new();
+        for i in 0..5000 {
+            let name = names.resolve_collision("IMG.jpg".to_string(), &rules);
+            let old_name = format!("IMG ({}).jpg", i);
+            names.rename(OsStr::new(&old_name), OsStr::new(&name), &rules);
+            assert!(resolved.insert(name));
+        }
+        assert!(resolved.contains("IMG_000.jpg"));
+        assert!(resolved.contains("IMG_999.jpg"));
+    }
+}
@@ -0,0 +1,168 @@
+//! Extract zip and tar archives, unixizing member paths on the fly
+
+use crate::archive::decoder;
+use crate::archive::lossy;
+use crate::archive::Format;
+use crate::archive::PathMap;
+use crate::opts::ExtractOpts;
+use crate::Result;
+
+use std::fs;
+use std::fs::File;
+use std::fs::Permissions;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::os::unix::fs::symlink;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Print the rename of the member `path` to `new_path`, if it changed
+fn print_rename(path: &str, new_path: &str) {
+    if path != new_path {
+        println!("rename '{}' -> '{}'", path, new_path);
+    }
+}
+
+/// Find where the sanitized member path `path` goes below `root`, or `None`
+/// if it refers to `root` itself. Its parents must be directories, and are
+/// created if missing and `create` is set. Existing symlinks are never
+/// followed, since they could lead outside of `root`.
+fn resolve(root: &Path, path: &str, create: bool) -> Result<Option<PathBuf>> {
+    let components: Vec<&str> = path
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    let (name, parents) = match components.split_last() {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+
+    let mut dest = root.to_path_buf();
+    for parent in parents {
+        dest.push(parent);
+        match fs::symlink_metadata(&dest) {
+            Ok(stat) if stat.is_dir() => {}
+            Err(err) if create && err.kind() == io::ErrorKind::NotFound => fs::create_dir(&dest)?,
+            _ => {
+                return Err(
+                    format!("cannot extract '{}': '{}' is not a directory", path, parent).into(),
+                )
+            }
+        }
+    }
+    dest.push(name);
+    Ok(Some(dest))
+}
+
+/// Create the missing parents of the sanitized member path `path` below
+/// `root`, and return where to extract it. A symlink where the member goes is
+/// removed, so that it is not written through.
+fn prepare(root: &Path, path: &str) -> Result<Option<PathBuf>> {
+    let dest = resolve(root, path, true)?;
+    if let Some(dest) = &dest {
+        if fs::symlink_metadata(dest).is_ok_and(|stat| stat.file_type().is_symlink()) {
+            fs::remove_file(dest)?;
+        }
+    }
+    Ok(dest)
+}
+
+/// Extract the tar archive `archive` to `root`, with paths mapped through
+/// `map`. Link targets are mapped like in `archive::copy_tar()`.
+fn extract_tar<R: Read>(
+    archive: &mut tar::Archive<R>,
+    root: &Path,
+    map: &mut PathMap,
+) -> Result<()> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_pax_global_extensions() {
+            continue;
+        }
+
+        let path = lossy(&entry.path_bytes());
+        let new_path = map.map(&path)?;
+        let dest = match prepare(root, &new_path)? {
+            Some(dest) => dest,
+            None => continue,
+        };
+        let target = entry.link_name_bytes().map(|target| lossy(&target));
+        match target {
+            Some(target) if entry_type.is_hard_link() => {
+                let new_target = map.map(&target)?;
+                let target_dest = resolve(root, &new_target, false)?
+                    .ok_or_else(|| format!("invalid hard link target '{}'", target))?;
+                fs::hard_link(target_dest, &dest)?;
+            }
+            Some(target) if entry_type.is_symlink() => {
+                let new_target = map.retarget(&path, &target).unwrap_or(target);
+                symlink(new_target, &dest)?;
+            }
+            _ if entry_type.is_file() || entry_type.is_dir() => {
+                entry.unpack(&dest)?;
+            }
+            _ => {
+                eprintln!("unf: warning: skipping special file '{}'", path);
+                continue;
+            }
+        }
+        print_rename(&path, &new_path);
+    }
+    Ok(())
+}
+
+/// Extract the zip archive `input` to `root`, with paths mapped through `map`
+fn extract_zip(input: File, root: &Path, map: &mut PathMap) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(input))?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let path = file.name().to_string();
+        let new_path = map.map(&path)?;
+        let dest = match prepare(root, &new_path)? {
+            Some(dest) => dest,
+            None => continue,
+        };
+
+        if file.is_dir() {
+            fs::create_dir_all(&dest)?;
+        } else if file.is_symlink() {
+            let mut target = String::new();
+            file.read_to_string(&mut target)?;
+            let new_target = map.retarget(&path, &target).unwrap_or(target);
+            symlink(new_target, &dest)?;
+        } else {
+            io::copy(&mut file, &mut File::create(&dest)?)?;
+            // Like for tar members, special bits are dropped so that
+            // extracting cannot create setuid files
+            if let Some(mode) = file.unix_mode() {
+                fs::set_permissions(&dest, Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+        print_rename(&path, &new_path);
+    }
+    Ok(())
+}
+
+/// Extract the archive in `opts`, unixizing each member path as it is
+/// extracted. Names that collide with other members or with existing entries
+/// get a collision-resolving number, so nothing is overwritten.
+pub fn extract(cwd: &Path, opts: &ExtractOpts) -> Result<()> {
+    let format = Format::from_path(&opts.archive)?;
+    let input = File::open(cwd.join(&opts.archive))?;
+    let root = cwd.join(&opts.dir);
+    if !root.is_dir() {
+        return Err(format!("'{}' is not a directory", opts.dir.display()).into());
+    }
+
+    let mut map = PathMap::in_dir(&root);
+    match format {
+        Format::Zip => extract_zip(input, &root, &mut map),
+        Format::Tar(compression) => {
+            let reader = decoder(BufReader::new(input), compression)?;
+            extract_tar(&mut tar::Archive::new(reader), &root, &mut map)
+        }
+    }
+}
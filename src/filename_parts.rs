@@ -1,5 +1,198 @@
+use crate::opts::Flags;
+
+use std::collections::HashMap;
+
+use regex::Regex;
+
 const FILENAME_NUM_DIGITS: usize = 3;
 
+/// Extensions made of several parts, which are kept together after the
+/// collision-resolving number
+const DEFAULT_COMPOUND_EXTS: &[&str] = &[
+    "tar.gz", "tar.bz2", "tar.xz", "tar.zst", "tar.lz", "tar.lzma", "user.js", "d.ts",
+];
+
+/// Extensions of subtitles, which are kept together with a preceding language
+/// code, like in `movie.en.srt`
+const SUBTITLE_EXTS: &[&str] = &["srt", "vtt", "ass", "ssa", "sub"];
+
+/// ISO 639-1 and ISO 639-2 language codes, which are kept together with a
+/// following subtitle extension
+const LANGUAGE_CODES: &[&str] = &[
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az", "ba", "be", "bg", "bh",
+    "bi", "bm", "bn", "bo", "br", "bs", "ca", "ce", "ch", "co", "cr", "cs", "cu", "cv", "cy", "da",
+    "de", "dv", "dz", "ee", "el", "en", "eo", "es", "et", "eu", "fa", "ff", "fi", "fj", "fo", "fr",
+    "fy", "ga", "gd", "gl", "gn", "gu", "gv", "ha", "he", "hi", "ho", "hr", "ht", "hu", "hy", "hz",
+    "ia", "id", "ie", "ig", "ii", "ik", "io", "is", "it", "iu", "ja", "jv", "ka", "kg", "ki", "kj",
+    "kk", "kl", "km", "kn", "ko", "kr", "ks", "ku", "kv", "kw", "ky", "la", "lb", "lg", "li", "ln",
+    "lo", "lt", "lu", "lv", "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms", "mt", "my", "na", "nb",
+    "nd", "ne", "ng", "nl", "nn", "no", "nr", "nv", "ny", "oc", "oj", "om", "or", "os", "pa", "pi",
+    "pl", "ps", "pt", "qu", "rm", "rn", "ro", "ru", "rw", "sa", "sc", "sd", "se", "sg", "si", "sk",
+    "sl", "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw", "ta", "te", "tg", "th", "ti",
+    "tk", "tl", "tn", "to", "tr", "ts", "tt", "tw", "ty", "ug", "uk", "ur", "uz", "ve", "vi", "vo",
+    "wa", "wo", "xh", "yi", "yo", "za", "zh", "zu", "aar", "abk", "afr", "aka", "alb", "amh",
+    "ara", "arg", "arm", "asm", "ava", "ave", "aym", "aze", "bak", "bam", "baq", "bel", "ben",
+    "bih", "bis", "bod", "bos", "bre", "bul", "bur", "cat", "ces", "cha", "che", "chi", "chu",
+    "chv", "cor", "cos", "cre", "cym", "cze", "dan", "deu", "div", "dut", "dzo", "ell", "eng",
+    "epo", "est", "eus", "ewe", "fao", "fas", "fij", "fil", "fin", "fra", "fre", "fry", "ful",
+    "geo", "ger", "gla", "gle", "glg", "glv", "gre", "grn", "guj", "hat", "hau", "heb", "her",
+    "hin", "hmo", "hrv", "hun", "hye", "ibo", "ice", "ido", "iii", "iku", "ile", "ina", "ind",
+    "ipk", "isl", "ita", "jav", "jpn", "kal", "kan", "kas", "kat", "kau", "kaz", "khm", "kik",
+    "kin", "kir", "kom", "kon", "kor", "kua", "kur", "lao", "lat", "lav", "lim", "lin", "lit",
+    "ltz", "lub", "lug", "mac", "mah", "mal", "mao", "mar", "may", "mkd", "mlg", "mlt", "mon",
+    "mri", "msa", "mya", "nau", "nav", "nbl", "nde", "ndo", "nep", "nld", "nno", "nob", "nor",
+    "nya", "oci", "oji", "ori", "orm", "oss", "pan", "per", "pli", "pol", "por", "pus", "que",
+    "roh", "ron", "rum", "run", "rus", "sag", "san", "sin", "slk", "slo", "slv", "sme", "smo",
+    "sna", "snd", "som", "sot", "spa", "sqi", "srd", "srp", "ssw", "sun", "swa", "swe", "tah",
+    "tam", "tat", "tel", "tgk", "tgl", "tha", "tib", "tir", "ton", "tsn", "tso", "tuk", "tur",
+    "twi", "uig", "ukr", "urd", "uzb", "ven", "vie", "vol", "wel", "wln", "wol", "xho", "yid",
+    "yor", "zha", "zho", "zul",
+];
+
+/// Synonyms of extensions replaced with `--normalize-ext`
+const EXT_SYNONYMS: &[(&str, &str)] = &[
+    ("jpeg", "jpg"),
+    ("jpe", "jpg"),
+    ("tiff", "tif"),
+    ("htm", "html"),
+    ("mpeg", "mpg"),
+];
+
+/// How extensions are rewritten, according to `--lowercase-ext`,
+/// `--normalize-ext`, and `--ext-map`
+#[derive(Debug, Default)]
+struct ExtRules {
+    /// Whether to lowercase extensions that are not mapped
+    lowercase: bool,
+
+    /// Replacements of extensions, keyed by lowercase extension
+    map: HashMap<String, String>,
+}
+
+impl ExtRules {
+    /// The extension `ext` is rewritten to, or `None` if it is kept
+    fn apply(&self, ext: &str) -> Option<String> {
+        match self.map.get(&ext.to_ascii_lowercase()) {
+            Some(to) => Some(to.clone()),
+            None if self.lowercase => Some(ext.to_ascii_lowercase()),
+            None => None,
+        }
+    }
+}
+
+/// How filenames are split and rewritten, according to `--compound-ext`,
+/// `--lowercase-ext`, `--normalize-ext`, `--ext-map`, `--replace-stem-dots`,
+/// and `--normalize-markers`
+#[derive(Debug)]
+pub struct NamingRules {
+    /// Compound extensions recognized when splitting filenames, in lowercase
+    /// and without the leading dot
+    compound_exts: Vec<String>,
+
+    /// Rules for rewriting extensions
+    ext_rules: ExtRules,
+
+    /// Whether to replace dots inside stems
+    replace_stem_dots: bool,
+
+    /// Whether to turn duplicate markers into collision-resolving numbers
+    normalize_markers: bool,
+}
+
+impl Default for NamingRules {
+    fn default() -> Self {
+        Self {
+            compound_exts: DEFAULT_COMPOUND_EXTS
+                .iter()
+                .map(|ext| ext.to_string())
+                .collect(),
+            ext_rules: ExtRules::default(),
+            replace_stem_dots: false,
+            normalize_markers: false,
+        }
+    }
+}
+
+impl NamingRules {
+    pub fn from_flags(flags: &Flags) -> Self {
+        let mut rules = Self::default();
+        rules.add_compound_exts(&flags.compound_ext);
+
+        rules.ext_rules.lowercase = flags.lowercase_ext || flags.normalize_ext;
+        if flags.normalize_ext {
+            for (from, to) in EXT_SYNONYMS {
+                rules.ext_rules.map.insert(from.to_string(), to.to_string());
+            }
+        }
+        for (from, to) in &flags.ext_map {
+            let from = from.trim_start_matches('.').to_ascii_lowercase();
+            let to = to.trim_start_matches('.').to_string();
+            rules.ext_rules.map.insert(from, to);
+        }
+        rules.replace_stem_dots = flags.replace_stem_dots;
+        rules.normalize_markers = flags.normalize_markers;
+        rules
+    }
+
+    /// Recognize the compound extensions `exts`, like `tar.gz`, in addition
+    /// to the default ones
+    fn add_compound_exts<S: AsRef<str>>(&mut self, exts: &[S]) {
+        for ext in exts {
+            let ext = ext.as_ref().trim_start_matches('.').to_ascii_lowercase();
+            if !self.compound_exts.contains(&ext) {
+                self.compound_exts.push(ext);
+            }
+        }
+    }
+
+    /// Split `filename` into the part before its extension and the
+    /// extension, or return `None` if it has no extension. Compound
+    /// extensions are matched case-insensitively, and only if something
+    /// precedes them.
+    fn split_ext<'f>(&self, filename: &'f str) -> Option<(&'f str, &'f str)> {
+        let lower = filename.to_ascii_lowercase();
+        let compound_len = self
+            .compound_exts
+            .iter()
+            .filter(|ext| lower.len() > ext.len() + 1 && lower.ends_with(&format!(".{}", ext)))
+            .map(|ext| ext.len())
+            .max();
+        if let Some(len) = compound_len {
+            let dot = filename.len() - len - 1;
+            return Some((&filename[..dot], &filename[dot + 1..]));
+        }
+
+        let (stem, ext) = filename.rsplit_once('.')?;
+        if SUBTITLE_EXTS.contains(&ext.to_ascii_lowercase().as_str()) {
+            if let Some((stem, lang)) = stem.rsplit_once('.') {
+                let is_lang = LANGUAGE_CODES.contains(&lang.to_ascii_lowercase().as_str());
+                if is_lang && !stem.is_empty() {
+                    return Some((stem, &filename[stem.len() + 1..]));
+                }
+            }
+        }
+        Some((stem, ext))
+    }
+}
+
+/// Replace the dots in `stem` with underscores, merging them with adjacent
+/// underscores and dropping trailing ones. A leading dot is kept.
+fn replace_stem_dots(stem: &str) -> String {
+    lazy_static! {
+        static ref RE_DOTS: Regex = Regex::new("[._]*\\.[._]*").unwrap();
+    }
+
+    let (leading_dot, rest) = match stem.strip_prefix('.') {
+        Some(rest) => (".", rest),
+        None => ("", stem),
+    };
+    let rest = RE_DOTS.replace_all(rest, "_");
+    match rest.trim_end_matches('_') {
+        "" => stem.to_string(),
+        rest => format!("{}{}", leading_dot, rest),
+    }
+}
+
 /// Struct representing a filename that can be split, modified, and
 /// merged back into a filename string
 #[derive(PartialEq, Eq, Debug)]
@@ -32,18 +225,46 @@ impl FilenameParts {
         )
     }
 
-    pub fn from_filename(filename: &str) -> Self {
-        let mut it = filename.rsplitn(2, '.');
-        let ext = it.next().expect("tried to split empty filename");
-        let maybe_stem_num = it.next();
+    /// Rewrite the extension according to `rules`. Dotfiles, which have no
+    /// stem, are left as they are.
+    pub fn normalize_ext(&mut self, rules: &NamingRules) {
+        if let Some(ext) = &mut self.ext {
+            if !self.stem.is_empty() {
+                if let Some(new_ext) = rules.ext_rules.apply(ext) {
+                    *ext = new_ext;
+                }
+            }
+        }
+    }
+
+    /// Replace the dots inside the stem with underscores if `rules` say so,
+    /// so that only the extension is separated by a dot
+    pub fn normalize_stem(&mut self, rules: &NamingRules) {
+        if rules.replace_stem_dots {
+            self.stem = replace_stem_dots(&self.stem);
+        }
+    }
+
+    /// Like `normalize_stem()`, for the name of a directory. Directories have
+    /// no extension, so every dot but a leading one is replaced, and the whole
+    /// name becomes the stem.
+    pub fn normalize_dir_name(&mut self, rules: &NamingRules) {
+        if rules.replace_stem_dots {
+            *self = Self {
+                stem: replace_stem_dots(&self.merge()),
+                num: None,
+                ext: None,
+            };
+        }
+    }
 
-        // Set the stem-num combination to the extension if the iterator
-        // said it was `None`. This is such that only the content after
-        // the final dot is considered the extension, but extension-less
-        // files are properly handled.
-        let (stem_num, ext) = match maybe_stem_num {
-            Some(stem_num) => (stem_num, Some(ext.to_string())),
-            None => (ext, None),
+    pub fn from_filename(filename: &str, rules: &NamingRules) -> Self {
+        // Only the content after the final dot is considered the extension,
+        // unless it is part of a compound extension like `tar.gz`, but
+        // extension-less files are properly handled
+        let (stem_num, ext) = match rules.split_ext(filename) {
+            Some((stem_num, ext)) => (stem_num, Some(ext.to_string())),
+            None => (filename, None),
         };
 
         // Hack to get an iterator over the last `FILENAME_NUM_DIGITS + 1`
@@ -77,16 +298,92 @@ impl FilenameParts {
 
         Self { stem, num, ext }
     }
+
+    /// Split a filename whose stem ends with a duplicate marker added by
+    /// browsers and file managers, like `name (1).ext`, `name(2).ext`,
+    /// `name - Copy.ext`, or `name copy 2.ext`. The marker is turned into the
+    /// equivalent collision-resolving number, with the first duplicate
+    /// numbered 0. Returns `None` if there is no marker, or if `rules` do not
+    /// normalize markers. A bare `-1` is not a marker, since it is as likely
+    /// to be part of a name like `chapter-1.md`.
+    pub fn from_duplicate(filename: &str, rules: &NamingRules) -> Option<Self> {
+        if !rules.normalize_markers {
+            return None;
+        }
+        Self::from_marker(filename, rules)
+    }
+
+    /// Like `from_duplicate()`, but whether or not `rules` normalize markers
+    pub fn from_marker(filename: &str, rules: &NamingRules) -> Option<Self> {
+        Self::parse_marker(filename, rules, false)
+    }
+
+    /// Split a filename whose stem ends with a bare `-N`, like `name-1.ext`,
+    /// like a duplicate marker if `rules` normalize markers. Such a suffix is
+    /// as likely to be part of the name, so it should only be taken as a
+    /// marker if the unmarked name exists next to it.
+    pub fn from_hyphen_marker(filename: &str, rules: &NamingRules) -> Option<Self> {
+        if !rules.normalize_markers {
+            return None;
+        }
+        Self::parse_marker(filename, rules, true)
+    }
+
+    /// Split a filename with a duplicate marker, either a bare `-N` if
+    /// `hyphen` is set, or any other marker otherwise
+    fn parse_marker(filename: &str, rules: &NamingRules, hyphen: bool) -> Option<Self> {
+        lazy_static! {
+            static ref RE_DUP_MARKER: Regex = Regex::new(concat!(
+                r"^(?P<stem>.+?)(?:",
+                r" ?\((?P<paren>[1-9][0-9]*)\)",
+                r"| - [Cc]opy(?: \((?P<win>[1-9][0-9]*)\))?",
+                r"| [Cc]opy (?P<mac>[1-9][0-9]*)",
+                r")$",
+            ))
+            .unwrap();
+            static ref RE_HYPHEN_MARKER: Regex =
+                Regex::new(r"^(?P<stem>.+?)-(?P<hyphen>[1-9][0-9]*)$").unwrap();
+        }
+
+        let Self { stem, ext, .. } = Self::from_filename(filename, rules);
+        let captures = if hyphen {
+            RE_HYPHEN_MARKER.captures(&stem)?
+        } else {
+            RE_DUP_MARKER.captures(&stem)?
+        };
+        let stem = captures["stem"].to_string();
+
+        // Numbers in markers are one more than collision-resolving numbers,
+        // and a copy without a number is the first duplicate
+        let num = match ["paren", "win", "mac", "hyphen"]
+            .iter()
+            .find_map(|name| captures.name(name))
+        {
+            Some(count) => count.as_str().parse::<usize>().ok()? - 1,
+            None => 0,
+        };
+        if num >= 10usize.pow(FILENAME_NUM_DIGITS as u32) {
+            return None;
+        }
+        Some(Self {
+            stem,
+            num: Some(num),
+            ext,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use clap::Parser;
+
     #[test]
     fn from_filename() {
+        let rules = NamingRules::default();
         assert_eq!(
-            FilenameParts::from_filename("a"),
+            FilenameParts::from_filename("a", &rules),
             FilenameParts {
                 stem: "a".to_string(),
                 num: None,
@@ -94,7 +391,7 @@ mod tests {
             }
         );
         assert_eq!(
-            FilenameParts::from_filename("a."),
+            FilenameParts::from_filename("a.", &rules),
             FilenameParts {
                 stem: "a".to_string(),
                 num: None,
@@ -102,7 +399,7 @@ mod tests {
             }
         );
         assert_eq!(
-            FilenameParts::from_filename(".a"),
+            FilenameParts::from_filename(".a", &rules),
             FilenameParts {
                 stem: "".to_string(),
                 num: None,
@@ -110,7 +407,7 @@ mod tests {
             }
         );
         assert_eq!(
-            FilenameParts::from_filename("a_0000"),
+            FilenameParts::from_filename("a_0000", &rules),
             FilenameParts {
                 stem: "a_0000".to_string(),
                 num: None,
@@ -118,7 +415,7 @@ mod tests {
             }
         );
         assert_eq!(
-            FilenameParts::from_filename("a_137"),
+            FilenameParts::from_filename("a_137", &rules),
             FilenameParts {
                 stem: "a".to_string(),
                 num: Some(137),
@@ -126,7 +423,7 @@ mod tests {
             }
         );
         assert_eq!(
-            FilenameParts::from_filename("a_000.txt"),
+            FilenameParts::from_filename("a_000.txt", &rules),
             FilenameParts {
                 stem: "a".to_string(),
                 num: Some(0),
@@ -134,7 +431,7 @@ mod tests {
             }
         );
         assert_eq!(
-            FilenameParts::from_filename("a____000.txt"),
+            FilenameParts::from_filename("a____000.txt", &rules),
             FilenameParts {
                 stem: "a___".to_string(),
                 num: Some(0),
@@ -142,7 +439,7 @@ mod tests {
             }
         );
         assert_eq!(
-            FilenameParts::from_filename(".x._._._222.txt"),
+            FilenameParts::from_filename(".x._._._222.txt", &rules),
             FilenameParts {
                 stem: ".x._._.".to_string(),
                 num: Some(222),
@@ -151,6 +448,145 @@ mod tests {
         );
     }
 
+    #[test]
+    fn from_filename_compound_ext() {
+        let parts = |stem: &str, num, ext: &str| FilenameParts {
+            stem: stem.to_string(),
+            num,
+            ext: Some(ext.to_string()),
+        };
+        let rules = NamingRules::default();
+        let f = |filename| FilenameParts::from_filename(filename, &rules);
+        assert_eq!(f("backup.tar.gz"), parts("backup", None, "tar.gz"));
+        assert_eq!(f("backup_001.TAR.ZST"), parts("backup", Some(1), "TAR.ZST"));
+        assert_eq!(f("script.user.js"), parts("script", None, "user.js"));
+        assert_eq!(f("index.d.ts"), parts("index", None, "d.ts"));
+        assert_eq!(f("movie_000.en.srt"), parts("movie", Some(0), "en.srt"));
+        assert_eq!(f("movie.part1.srt"), parts("movie.part1", None, "srt"));
+        assert_eq!(f("Movie.PT.srt"), parts("Movie", None, "PT.srt"));
+        assert_eq!(f("movie.fre.ass"), parts("movie", None, "fre.ass"));
+        assert_eq!(f("The.End.srt"), parts("The.End", None, "srt"));
+        assert_eq!(f(".tar.gz"), parts(".tar", None, "gz"));
+        assert_eq!(f("a.en.txt"), parts("a.en", None, "txt"));
+
+        assert_eq!(f("a.pkg.tar"), parts("a.pkg", None, "tar"));
+
+        let mut rules = NamingRules::default();
+        rules.add_compound_exts(&[".Pkg.TAR"]);
+        let f = |filename| FilenameParts::from_filename(filename, &rules);
+        assert_eq!(f("a.pkg.tar"), parts("a", None, "pkg.tar"));
+        assert_eq!(f("backup.tar.gz"), parts("backup", None, "tar.gz"));
+    }
+
+    #[test]
+    fn stem_dots() {
+        let rules = NamingRules {
+            replace_stem_dots: true,
+            ..NamingRules::default()
+        };
+        let f = |filename: &str| {
+            let mut parts = FilenameParts::from_filename(filename, &rules);
+            parts.normalize_stem(&rules);
+            parts.merge()
+        };
+        assert_eq!(f("my.file.name.txt"), "my_file_name.txt");
+        assert_eq!(f("Show.S01E02.720p.mkv"), "Show_S01E02_720p.mkv");
+        assert_eq!(f("backup.2021.tar.gz"), "backup_2021.tar.gz");
+        assert_eq!(f("Show.S01E02.en.srt"), "Show_S01E02.en.srt");
+        assert_eq!(f("a._.b_000.txt"), "a_b_000.txt");
+        assert_eq!(f("a..txt"), "a.txt");
+        assert_eq!(f(".config.old.bak"), ".config_old.bak");
+        assert_eq!(f(".bashrc"), ".bashrc");
+        assert_eq!(f("plain.txt"), "plain.txt");
+
+        let f = |filename: &str| {
+            let mut parts = FilenameParts::from_filename(filename, &rules);
+            parts.normalize_dir_name(&rules);
+            parts.merge()
+        };
+        assert_eq!(f("Show.S01.720p.WEB"), "Show_S01_720p_WEB");
+        assert_eq!(f("Album.2020_000.FLAC"), "Album_2020_000_FLAC");
+        assert_eq!(f(".config.d"), ".config_d");
+        assert_eq!(f(".config"), ".config");
+        assert_eq!(f("plain"), "plain");
+    }
+
+    #[test]
+    fn ext_rules() {
+        let mut rules = ExtRules::default();
+        assert_eq!(rules.apply("JPG"), None);
+
+        rules.lowercase = true;
+        rules.map.insert("jpeg".to_string(), "jpg".to_string());
+        rules.map.insert("markdown".to_string(), "MD".to_string());
+        assert_eq!(rules.apply("JPG"), Some("jpg".to_string()));
+        assert_eq!(rules.apply("JPEG"), Some("jpg".to_string()));
+        assert_eq!(rules.apply("Markdown"), Some("MD".to_string()));
+        assert_eq!(rules.apply("TAR.GZ"), Some("tar.gz".to_string()));
+    }
+
+    #[test]
+    fn ext_rules_from_flags() {
+        let ext = |rules: &NamingRules, filename| {
+            let mut parts = FilenameParts::from_filename(filename, rules);
+            parts.normalize_ext(rules);
+            parts.ext
+        };
+        let rules = NamingRules::default();
+        assert_eq!(ext(&rules, "a.JPEG"), Some("JPEG".to_string()));
+
+        let flags = Flags::parse_from(["unf", "--normalize-ext", "--ext-map", ".JPEG=.jpeg"]);
+        let rules = NamingRules::from_flags(&flags);
+        assert_eq!(ext(&rules, "a.JPEG"), Some("jpeg".to_string()));
+        assert_eq!(ext(&rules, "a.Tiff"), Some("tif".to_string()));
+        assert_eq!(ext(&rules, "a.TXT"), Some("txt".to_string()));
+        assert_eq!(ext(&rules, ".JPEG"), Some("JPEG".to_string()));
+    }
+
+    #[test]
+    fn from_duplicate() {
+        let parts = |stem: &str, num, ext: Option<&str>| {
+            Some(FilenameParts {
+                stem: stem.to_string(),
+                num: Some(num),
+                ext: ext.map(str::to_string),
+            })
+        };
+        let rules = NamingRules {
+            normalize_markers: true,
+            ..NamingRules::default()
+        };
+        let f = |filename| FilenameParts::from_duplicate(filename, &rules);
+        assert_eq!(f("name (1).ext"), parts("name", 0, Some("ext")));
+        assert_eq!(f("name(2).ext"), parts("name", 1, Some("ext")));
+        assert_eq!(f("name - Copy.ext"), parts("name", 0, Some("ext")));
+        assert_eq!(f("name - Copy (2).ext"), parts("name", 1, Some("ext")));
+        assert_eq!(f("name copy 2.ext"), parts("name", 1, Some("ext")));
+        assert_eq!(f("name (12)"), parts("name", 11, None));
+        assert_eq!(f("name.ext"), None);
+        assert_eq!(f("name (0).ext"), None);
+        assert_eq!(f("name (1001).ext"), None);
+        assert_eq!(f("(1).ext"), None);
+        assert_eq!(f("2021-10.ext"), None);
+        assert_eq!(f("name copy.ext"), None);
+        assert_eq!(f("name-1.ext"), None);
+        assert_eq!(f("chapter-1.md"), None);
+
+        let f = |filename| FilenameParts::from_hyphen_marker(filename, &rules);
+        assert_eq!(f("name-1.ext"), parts("name", 0, Some("ext")));
+        assert_eq!(f("chapter-12"), parts("chapter", 11, None));
+        assert_eq!(f("name (1).ext"), None);
+        assert_eq!(f("name-0.ext"), None);
+        assert_eq!(f("-1.ext"), None);
+
+        let rules = NamingRules::default();
+        assert_eq!(FilenameParts::from_duplicate("name (1).ext", &rules), None);
+        assert_eq!(
+            FilenameParts::from_hyphen_marker("name-1.ext", &rules),
+            None
+        );
+    }
+
     #[test]
     fn merge() {
         assert_eq!(
@@ -0,0 +1,587 @@
+//! Filesystem operations needed by `unf` that `rsfs` does not provide
+
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::read_children_names;
+use crate::EntryKind;
+use crate::Result;
+
+use rsfs::unix_ext::GenFSExt;
+use rsfs::GenFS;
+use rsfs::Metadata;
+
+/// Identity of a filesystem entry, as a device and inode number pair
+pub type FileId = (u64, u64);
+
+/// Extension of `GenFS` with the extra operations `unf` needs
+pub trait FSExt: GenFS + GenFSExt {
+    /// Get the identity of the entry at `path`, following symlinks. Two paths
+    /// have the same identity if and only if they refer to the same entry.
+    fn file_id<P: AsRef<Path>>(&self, path: P) -> io::Result<FileId>;
+
+    /// Move the file at `path` to the trash
+    fn trash<P: AsRef<Path>>(&self, path: P) -> io::Result<()>;
+
+    /// Handle to an open directory, which entries are renamed relative to
+    type Dir;
+
+    /// Open the directory at `path`, following symlinks
+    fn open_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::Dir>;
+
+    /// Open the directory `name` in `dir`. Unless `follow_symlinks` is set,
+    /// this fails if `name` is a symlink, so that a directory swapped for a
+    /// symlink cannot lead outside of the tree.
+    fn open_dir_at(
+        &self,
+        dir: &Self::Dir,
+        name: &OsStr,
+        follow_symlinks: bool,
+    ) -> io::Result<Self::Dir>;
+
+    /// Rename `from` in `from_dir` to `to` in `to_dir`, failing with
+    /// `AlreadyExists` instead of replacing an existing entry
+    fn rename_at(
+        &self,
+        from_dir: &Self::Dir,
+        from: &OsStr,
+        to_dir: &Self::Dir,
+        to: &OsStr,
+    ) -> io::Result<()>;
+
+    /// Remove the file `name` in `dir`
+    fn remove_file_at(&self, dir: &Self::Dir, name: &OsStr) -> io::Result<()>;
+
+    /// Read the names of the entries in `dir`, in no particular order
+    fn read_dir_at(&self, dir: &Self::Dir) -> io::Result<Vec<OsString>>;
+
+    /// Get the kind of the entry `name` in `dir`, following a symlink only
+    /// with `follow_symlinks`
+    fn kind_at(
+        &self,
+        dir: &Self::Dir,
+        name: &OsStr,
+        follow_symlinks: bool,
+    ) -> io::Result<EntryKind>;
+}
+
+/// Convert `name` for passing to libc
+fn c_string(name: &OsStr) -> io::Result<CString> {
+    Ok(CString::new(name.as_bytes())?)
+}
+
+/// Return the last OS error if `ret` is -1
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Open the directory `path` relative to the directory `dir`
+fn open_dir_fd(dir: RawFd, path: &OsStr, follow_symlinks: bool) -> io::Result<OwnedFd> {
+    let mut flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    if !follow_symlinks {
+        flags |= libc::O_NOFOLLOW;
+    }
+    let path = c_string(path)?;
+    let fd = check(unsafe { libc::openat(dir, path.as_ptr(), flags) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Rename `from` in `from_dir` to `to` in `to_dir` after checking that `to`
+/// does not exist, leaving a small window for races
+fn renameat_checked(from_dir: RawFd, from: &CStr, to_dir: RawFd, to: &CStr) -> io::Result<()> {
+    let mut stat = MaybeUninit::uninit();
+    let flags = libc::AT_SYMLINK_NOFOLLOW;
+    if unsafe { libc::fstatat(to_dir, to.as_ptr(), stat.as_mut_ptr(), flags) } == 0 {
+        return Err(io::ErrorKind::AlreadyExists.into());
+    }
+    check(unsafe { libc::renameat(from_dir, from.as_ptr(), to_dir, to.as_ptr()) })?;
+    Ok(())
+}
+
+/// Rename `from` in `from_dir` to `to` in `to_dir` with
+/// `renameat2(RENAME_NOREPLACE)`, so that an entry created by another process
+/// after checking for collisions is never replaced. Falls back to
+/// `renameat_checked()` if the filesystem or kernel does not support it.
+#[cfg(target_os = "linux")]
+fn renameat_noreplace(from_dir: RawFd, from: &CStr, to_dir: RawFd, to: &CStr) -> io::Result<()> {
+    let ret = unsafe {
+        libc::renameat2(
+            from_dir,
+            from.as_ptr(),
+            to_dir,
+            to.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    match check(ret) {
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) => {
+            renameat_checked(from_dir, from, to_dir, to)
+        }
+        result => result.map(drop),
+    }
+}
+
+/// Pointer to `errno`, which has to be cleared to tell the end of a directory
+/// from an error in `readdir()`
+#[cfg(any(target_os = "linux", target_os = "emscripten"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno()
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly"
+))]
+unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__error()
+}
+
+/// Read the names of the entries in the directory `dir` with `readdir()`,
+/// through a duplicate of it that is rewound first, since `fdopendir()` takes
+/// over the descriptor it is given and duplicates share their offset
+fn read_dir_fd(dir: RawFd) -> io::Result<Vec<OsString>> {
+    let fd = check(unsafe { libc::fcntl(dir, libc::F_DUPFD_CLOEXEC, 0) })?;
+    let dirp = unsafe { libc::fdopendir(fd) };
+    if dirp.is_null() {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(err);
+    }
+    unsafe { libc::rewinddir(dirp) };
+
+    let mut names = Vec::new();
+    let result = loop {
+        unsafe { *errno_location() = 0 };
+        let ent = unsafe { libc::readdir(dirp) };
+        if ent.is_null() {
+            match io::Error::last_os_error() {
+                err if err.raw_os_error() == Some(0) => break Ok(names),
+                err => break Err(err),
+            }
+        }
+        let name = unsafe { CStr::from_ptr((*ent).d_name.as_ptr()) }.to_bytes();
+        if name != b"." && name != b".." {
+            names.push(OsStr::from_bytes(name).to_os_string());
+        }
+    };
+    unsafe { libc::closedir(dirp) };
+    result
+}
+
+/// Get the kind of the entry `name` in the directory `dir` with `fstatat()`
+fn kind_fd(dir: RawFd, name: &OsStr, follow_symlinks: bool) -> io::Result<EntryKind> {
+    let name = c_string(name)?;
+    let flags = if follow_symlinks {
+        0
+    } else {
+        libc::AT_SYMLINK_NOFOLLOW
+    };
+    let mut stat = MaybeUninit::uninit();
+    check(unsafe { libc::fstatat(dir, name.as_ptr(), stat.as_mut_ptr(), flags) })?;
+    let mode = unsafe { stat.assume_init() }.st_mode & libc::S_IFMT;
+    Ok(match mode {
+        libc::S_IFLNK => EntryKind::Symlink,
+        libc::S_IFDIR => EntryKind::Dir,
+        libc::S_IFREG => EntryKind::File,
+        _ => EntryKind::Special,
+    })
+}
+
+/// `renameat2()` is Linux-only, so elsewhere the target is checked first
+#[cfg(not(target_os = "linux"))]
+fn renameat_noreplace(from_dir: RawFd, from: &CStr, to_dir: RawFd, to: &CStr) -> io::Result<()> {
+    renameat_checked(from_dir, from, to_dir, to)
+}
+
+/// Renames are done with `renameat_noreplace()`, so that existing entries are
+/// never replaced
+impl FSExt for rsfs::disk::FS {
+    fn file_id<P: AsRef<Path>>(&self, path: P) -> io::Result<FileId> {
+        let stat = std::fs::metadata(path)?;
+        Ok((stat.dev(), stat.ino()))
+    }
+
+    /// Uses the trash of the desktop environment, following the freedesktop.org
+    /// trash specification
+    fn trash<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        trash::delete(path).map_err(|err| io::Error::other(err.to_string()))
+    }
+
+    type Dir = OwnedFd;
+
+    fn open_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<OwnedFd> {
+        open_dir_fd(libc::AT_FDCWD, path.as_ref().as_os_str(), true)
+    }
+
+    fn open_dir_at(
+        &self,
+        dir: &OwnedFd,
+        name: &OsStr,
+        follow_symlinks: bool,
+    ) -> io::Result<OwnedFd> {
+        open_dir_fd(dir.as_raw_fd(), name, follow_symlinks)
+    }
+
+    fn rename_at(
+        &self,
+        from_dir: &OwnedFd,
+        from: &OsStr,
+        to_dir: &OwnedFd,
+        to: &OsStr,
+    ) -> io::Result<()> {
+        let (from, to) = (c_string(from)?, c_string(to)?);
+        renameat_noreplace(from_dir.as_raw_fd(), &from, to_dir.as_raw_fd(), &to)
+    }
+
+    fn remove_file_at(&self, dir: &OwnedFd, name: &OsStr) -> io::Result<()> {
+        let name = c_string(name)?;
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })?;
+        Ok(())
+    }
+
+    fn read_dir_at(&self, dir: &OwnedFd) -> io::Result<Vec<OsString>> {
+        read_dir_fd(dir.as_raw_fd())
+    }
+
+    fn kind_at(&self, dir: &OwnedFd, name: &OsStr, follow_symlinks: bool) -> io::Result<EntryKind> {
+        kind_fd(dir.as_raw_fd(), name, follow_symlinks)
+    }
+}
+
+/// `FSExt::open_dir()` for filesystems without directory handles, where the
+/// handle is the path of the directory
+pub fn path_open_dir<FS: GenFS>(fs: &FS, path: &Path) -> io::Result<PathBuf> {
+    if !fs.metadata(path)?.is_dir() {
+        return Err(io::ErrorKind::NotADirectory.into());
+    }
+    Ok(path.to_path_buf())
+}
+
+/// `FSExt::open_dir_at()` for filesystems without directory handles
+pub fn path_open_dir_at<FS: GenFS>(
+    fs: &FS,
+    dir: &Path,
+    name: &OsStr,
+    follow_symlinks: bool,
+) -> io::Result<PathBuf> {
+    let path = dir.join(name);
+    let stat = if follow_symlinks {
+        fs.metadata(&path)?
+    } else {
+        fs.symlink_metadata(&path)?
+    };
+    if !stat.is_dir() {
+        return Err(io::ErrorKind::NotADirectory.into());
+    }
+    Ok(path)
+}
+
+/// `FSExt::rename_at()` for filesystems without directory handles. These are
+/// kept in memory where no other process can change them, so checking for the
+/// target first is enough.
+pub fn path_rename_at<FS: GenFS>(
+    fs: &FS,
+    from_dir: &Path,
+    from: &OsStr,
+    to_dir: &Path,
+    to: &OsStr,
+) -> io::Result<()> {
+    let to = to_dir.join(to);
+    if fs.symlink_metadata(&to).is_ok() {
+        return Err(io::ErrorKind::AlreadyExists.into());
+    }
+    fs.rename(from_dir.join(from), to)
+}
+
+/// `FSExt::read_dir_at()` for filesystems without directory handles
+pub fn path_read_dir_at<FS: GenFS>(fs: &FS, dir: &Path) -> io::Result<Vec<OsString>> {
+    fs.read_dir(dir)?
+        .map(|ent| ent.map(|ent| rsfs::DirEntry::file_name(&ent)))
+        .collect()
+}
+
+/// `FSExt::kind_at()` for filesystems without directory handles
+pub fn path_kind_at<FS: GenFS>(
+    fs: &FS,
+    dir: &Path,
+    name: &OsStr,
+    follow_symlinks: bool,
+) -> io::Result<EntryKind> {
+    let path = dir.join(name);
+    let stat = if follow_symlinks {
+        fs.metadata(&path)?
+    } else {
+        fs.symlink_metadata(&path)?
+    };
+    Ok(EntryKind::from_file_type(stat.file_type()))
+}
+
+/// Open the directory at `path` below the directory `base`. Only `base` is
+/// opened by path, and the components of `path` are opened one at a time with
+/// `FSExt::open_dir_at()`, so that symlinks among them are only followed with
+/// `follow_symlinks`.
+pub fn open_dir_below<FS: FSExt>(
+    fs: &FS,
+    base: &Path,
+    path: &Path,
+    follow_symlinks: bool,
+) -> io::Result<FS::Dir> {
+    let mut dir = fs.open_dir(base)?;
+    for component in path.components() {
+        dir = match component {
+            Component::CurDir => continue,
+            Component::RootDir => fs.open_dir("/")?,
+            _ => fs.open_dir_at(&dir, component.as_os_str(), follow_symlinks)?,
+        };
+    }
+    Ok(dir)
+}
+
+/// Split `path` into its parent and final component
+pub fn split_parent(path: &Path) -> io::Result<(&Path, &OsStr)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, name)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot rename '{}'", path.display()),
+        )),
+    }
+}
+
+/// Rename `from` to `to` like `FSExt::rename_at()`. Both are relative to
+/// `base`, and their parents are opened with `open_dir_below()`.
+pub fn rename_noreplace<FS: FSExt>(
+    fs: &FS,
+    base: &Path,
+    from: &Path,
+    to: &Path,
+    follow_symlinks: bool,
+) -> io::Result<()> {
+    let (from_parent, from_name) = split_parent(from)?;
+    let (to_parent, to_name) = split_parent(to)?;
+    let from_dir = open_dir_below(fs, base, from_parent, follow_symlinks)?;
+    let to_dir = open_dir_below(fs, base, to_parent, follow_symlinks)?;
+    fs.rename_at(&from_dir, from_name, &to_dir, to_name)
+}
+
+impl FSExt for rsfs::mem::FS {
+    /// The in-memory filesystem has no inode numbers, so this hashes the
+    /// canonicalized path instead. The device number is always zero.
+    fn file_id<P: AsRef<Path>>(&self, path: P) -> io::Result<FileId> {
+        let mut hasher = DefaultHasher::new();
+        self.canonicalize(path)?.hash(&mut hasher);
+        Ok((0, hasher.finish()))
+    }
+
+    /// Files are simply removed, since there is no trash in memory
+    fn trash<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.remove_file(path)
+    }
+
+    type Dir = PathBuf;
+
+    fn open_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<PathBuf> {
+        path_open_dir(self, path.as_ref())
+    }
+
+    fn open_dir_at(
+        &self,
+        dir: &PathBuf,
+        name: &OsStr,
+        follow_symlinks: bool,
+    ) -> io::Result<PathBuf> {
+        path_open_dir_at(self, dir, name, follow_symlinks)
+    }
+
+    fn rename_at(
+        &self,
+        from_dir: &PathBuf,
+        from: &OsStr,
+        to_dir: &PathBuf,
+        to: &OsStr,
+    ) -> io::Result<()> {
+        path_rename_at(self, from_dir, from, to_dir, to)
+    }
+
+    fn remove_file_at(&self, dir: &PathBuf, name: &OsStr) -> io::Result<()> {
+        self.remove_file(dir.join(name))
+    }
+
+    fn read_dir_at(&self, dir: &PathBuf) -> io::Result<Vec<OsString>> {
+        path_read_dir_at(self, dir)
+    }
+
+    fn kind_at(&self, dir: &PathBuf, name: &OsStr, follow_symlinks: bool) -> io::Result<EntryKind> {
+        path_kind_at(self, dir, name, follow_symlinks)
+    }
+}
+
+/// Call `f` on `path` and, if `recursive` is set and `path` is a directory, on
+/// everything below it in sorted order. Symlinks are not followed.
+pub fn walk<FS, F>(fs: &FS, cwd: &Path, path: &Path, recursive: bool, f: &mut F) -> Result<()>
+where
+    FS: FSExt,
+    F: FnMut(&Path, &FS::Metadata) -> Result<()>,
+{
+    let stat = fs.symlink_metadata(cwd.join(path))?;
+    f(path, &stat)?;
+    if recursive && stat.is_dir() {
+        for file_name in read_children_names(fs, cwd, path)? {
+            walk(fs, cwd, &path.join(file_name), recursive, f)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_file_id() {
+        let fs = rsfs::disk::FS;
+        let root = TempDir::new().unwrap();
+        let root = root.path();
+        test_file_id_fs(&fs, root);
+
+        let fs = rsfs::mem::FS::new();
+        let root = Path::new("/");
+        test_file_id_fs(&fs, root);
+    }
+
+    fn test_file_id_fs<FS: FSExt>(fs: &FS, root: &Path) {
+        fs.create_dir(root.join("a")).unwrap();
+        fs.create_dir(root.join("b")).unwrap();
+        fs.symlink("a", root.join("link")).unwrap();
+
+        let id = |path: &str| fs.file_id(root.join(path)).unwrap();
+        assert_eq!(id("a"), id("link"));
+        assert_eq!(id("a"), id("b/../a"));
+        assert_ne!(id("a"), id("b"));
+    }
+
+    #[test]
+    fn test_rename_at() {
+        let fs = rsfs::disk::FS;
+        let root = TempDir::new().unwrap();
+        let root = root.path();
+        test_rename_at_fs(&fs, root);
+
+        let fs = rsfs::mem::FS::new();
+        let root = Path::new("/");
+        test_rename_at_fs(&fs, root);
+    }
+
+    #[test]
+    fn test_read_dir_at() {
+        let fs = rsfs::disk::FS;
+        let root = TempDir::new().unwrap();
+        let root = root.path();
+        test_read_dir_at_fs(&fs, root);
+
+        // Handles of open directories keep referring to them after they are
+        // swapped
+        let dir = fs.open_dir(root.join("dir")).unwrap();
+        fs.rename(root.join("dir"), root.join("moved")).unwrap();
+        fs.create_dir(root.join("dir")).unwrap();
+        let mut names = fs.read_dir_at(&dir).unwrap();
+        names.sort();
+        assert_eq!(names, ["a", "link", "sub"]);
+        assert_eq!(
+            fs.kind_at(&dir, OsStr::new("a"), false).unwrap(),
+            EntryKind::File
+        );
+
+        let fs = rsfs::mem::FS::new();
+        let root = Path::new("/");
+        test_read_dir_at_fs(&fs, root);
+    }
+
+    fn test_read_dir_at_fs<FS: FSExt>(fs: &FS, root: &Path) {
+        fs.create_dir_all(root.join("dir/sub")).unwrap();
+        fs.create_file(root.join("dir/a")).unwrap();
+        fs.symlink("sub", root.join("dir/link")).unwrap();
+
+        let dir = fs.open_dir(root.join("dir")).unwrap();
+        for _ in 0..2 {
+            let mut names = fs.read_dir_at(&dir).unwrap();
+            names.sort();
+            assert_eq!(names, ["a", "link", "sub"]);
+        }
+
+        let kind = |name: &str, follow_symlinks| {
+            fs.kind_at(&dir, OsStr::new(name), follow_symlinks).unwrap()
+        };
+        assert_eq!(kind("a", false), EntryKind::File);
+        assert_eq!(kind("sub", false), EntryKind::Dir);
+        assert_eq!(kind("link", false), EntryKind::Symlink);
+        assert_eq!(kind("link", true), EntryKind::Dir);
+        assert!(fs.kind_at(&dir, OsStr::new("missing"), false).is_err());
+    }
+
+    fn test_rename_at_fs<FS: FSExt>(fs: &FS, root: &Path) {
+        fs.create_dir(root.join("dir")).unwrap();
+        fs.create_file(root.join("dir/a")).unwrap();
+        fs.create_file(root.join("dir/b")).unwrap();
+        fs.symlink("dir", root.join("link")).unwrap();
+
+        let root_dir = fs.open_dir(root).unwrap();
+        let dir = fs.open_dir_at(&root_dir, OsStr::new("dir"), false).unwrap();
+        let err = fs
+            .rename_at(&dir, OsStr::new("a"), &dir, OsStr::new("b"))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        fs.rename_at(&dir, OsStr::new("a"), &root_dir, OsStr::new("c"))
+            .unwrap();
+        assert!(fs.metadata(root.join("c")).is_ok());
+        assert!(fs.metadata(root.join("dir/b")).is_ok());
+
+        // Symlinks are only opened when following them
+        assert!(fs
+            .open_dir_at(&root_dir, OsStr::new("link"), false)
+            .is_err());
+        let link = fs.open_dir_at(&root_dir, OsStr::new("link"), true).unwrap();
+        fs.rename_at(&link, OsStr::new("b"), &link, OsStr::new("d"))
+            .unwrap();
+        assert!(fs.metadata(root.join("dir/d")).is_ok());
+
+        // Parents are not reached through symlinks unless following them
+        let (from, to) = (Path::new("link/d"), Path::new("link/e"));
+        assert!(rename_noreplace(fs, root, from, to, false).is_err());
+        rename_noreplace(fs, root, from, to, true).unwrap();
+        rename_noreplace(fs, root, Path::new("./dir/e"), Path::new("f"), false).unwrap();
+        assert!(fs.metadata(root.join("f")).is_ok());
+
+        fs.remove_file_at(&root_dir, OsStr::new("f")).unwrap();
+        assert!(fs.metadata(root.join("f")).is_err());
+    }
+}
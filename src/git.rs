@@ -0,0 +1,157 @@
+//! Rename files tracked by git through the index, like `git mv`
+
+use crate::renames::normalize;
+use crate::Result;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+/// Run `git` in `dir` with `args`, returning its standard output, or `None`
+/// if it exits unsuccessfully
+fn git_output(dir: &Path, args: &[&str]) -> Result<Option<Vec<u8>>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stderr(Stdio::null())
+        .output()?;
+    Ok(output.status.success().then_some(output.stdout))
+}
+
+/// Resolve `path` relative to `cwd` to an absolute path whose parent has no
+/// symlinks, like the paths git reports. The final component is kept, so that
+/// a symlink is not confused with its target.
+fn resolve(cwd: &Path, path: &Path) -> Result<PathBuf> {
+    let path = normalize(cwd, path);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok(std::fs::canonicalize(parent)?.join(name)),
+        _ => Ok(path),
+    }
+}
+
+/// Knowledge about the git working trees that renamed paths lie in
+#[derive(Debug, Default)]
+pub struct Git {
+    /// Working tree root of each directory looked up so far, or `None` for
+    /// directories outside any working tree
+    toplevels: HashMap<PathBuf, Option<PathBuf>>,
+
+    /// Absolute paths of tracked files and of directories containing tracked
+    /// files, for each working tree root
+    tracked: HashMap<PathBuf, HashSet<PathBuf>>,
+}
+
+impl Git {
+    /// Find the root of the working tree containing the directory `dir`
+    fn toplevel(&mut self, dir: &Path) -> Result<Option<PathBuf>> {
+        if let Some(toplevel) = self.toplevels.get(dir) {
+            return Ok(toplevel.clone());
+        }
+
+        let toplevel = git_output(dir, &["rev-parse", "--show-toplevel"])?.map(|stdout| {
+            let stdout = stdout.strip_suffix(b"\n").unwrap_or(&stdout);
+            PathBuf::from(OsStr::from_bytes(stdout))
+        });
+        self.toplevels.insert(dir.to_path_buf(), toplevel.clone());
+        Ok(toplevel)
+    }
+
+    /// Load the set of tracked paths of the working tree rooted at `toplevel`
+    fn load_tracked(toplevel: &Path) -> Result<HashSet<PathBuf>> {
+        let stdout = git_output(toplevel, &["ls-files", "-z"])?
+            .ok_or("failed to list files tracked by git")?;
+
+        let mut tracked = HashSet::new();
+        for file in stdout.split(|&b| b == 0).filter(|file| !file.is_empty()) {
+            let path = toplevel.join(OsStr::from_bytes(file));
+            for ancestor in path.ancestors() {
+                if ancestor == toplevel || !tracked.insert(ancestor.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+        Ok(tracked)
+    }
+
+    /// Returns `true` if `path` is a file tracked by git, or a directory
+    /// containing tracked files. Symlinks in the parents of `path` are
+    /// resolved first.
+    pub fn is_tracked(&mut self, cwd: &Path, path: &Path) -> Result<bool> {
+        let path = resolve(cwd, path)?;
+        let parent = match path.parent() {
+            Some(parent) => parent,
+            None => return Ok(false),
+        };
+        let toplevel = match self.toplevel(parent)? {
+            Some(toplevel) => toplevel,
+            None => return Ok(false),
+        };
+
+        if !self.tracked.contains_key(&toplevel) {
+            let tracked = Self::load_tracked(&toplevel)?;
+            self.tracked.insert(toplevel.clone(), tracked);
+        }
+        Ok(self.tracked[&toplevel].contains(&path))
+    }
+
+    /// Rename the tracked path `from` to `to` with `git mv`, updating the
+    /// index so that history is kept. Symlinks in the parents of both paths
+    /// are resolved first, so that they are seen inside the working tree.
+    /// The moved paths are then known as tracked under their new names, so
+    /// that they can be moved back through git too.
+    pub fn mv(&mut self, cwd: &Path, from: &Path, to: &Path) -> Result<()> {
+        let (resolved_from, resolved_to) = (resolve(cwd, from)?, resolve(cwd, to)?);
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(resolved_from.parent().unwrap_or(cwd))
+            .args(["mv", "--"])
+            .arg(&resolved_from)
+            .arg(&resolved_to)
+            .status()?;
+        if !status.success() {
+            return Err(format!("git mv '{}' failed", from.display()).into());
+        }
+        self.record_mv(&resolved_from, &resolved_to);
+        Ok(())
+    }
+
+    /// Update the tracked paths after the resolved path `from` was moved to
+    /// `to`, along with everything below it. Former parents of `from` that no
+    /// longer contain tracked files are forgotten.
+    fn record_mv(&mut self, from: &Path, to: &Path) {
+        for (toplevel, tracked) in &mut self.tracked {
+            let moved: Vec<PathBuf> = tracked
+                .iter()
+                .filter(|path| path.starts_with(from))
+                .cloned()
+                .collect();
+            if moved.is_empty() {
+                continue;
+            }
+
+            for path in moved {
+                tracked.remove(&path);
+                tracked.insert(to.join(path.strip_prefix(from).unwrap()));
+            }
+            for ancestor in to.ancestors().skip(1) {
+                if ancestor == toplevel || !tracked.insert(ancestor.to_path_buf()) {
+                    break;
+                }
+            }
+            for ancestor in from.ancestors().skip(1) {
+                if ancestor == toplevel
+                    || tracked.iter().any(|path| path.parent() == Some(ancestor))
+                {
+                    break;
+                }
+                tracked.remove(ancestor);
+            }
+        }
+    }
+}
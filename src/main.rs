@@ -1,101 +1,161 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod apply;
+mod archive;
+mod dir_index;
+mod extract;
 mod filename_parts;
-mod mem_fs;
+mod fs_ext;
+mod git;
 mod opts;
+mod overlay_fs;
+mod planner;
+mod prompt;
+mod refs;
+mod renames;
+mod script;
+mod signals;
+mod symlinks;
+mod tui;
+#[cfg(all(feature = "watch", target_os = "linux"))]
+mod watch;
 
+use dir_index::DirIndex;
+use dir_index::DirNames;
 use filename_parts::FilenameParts;
+use filename_parts::NamingRules;
+use fs_ext::FSExt;
+use fs_ext::FileId;
+use git::Git;
+use opts::Command;
+use opts::DuplicateAction;
+use opts::EntryType;
 use opts::Flags;
 use opts::Opts;
+use opts::SpecialPolicy;
+use planner::Planner;
+use prompt::Answer;
+use prompt::Confirm;
+use prompt::Prompter;
+use renames::Rename;
 
 use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::ffi::OsString;
+use std::io;
+use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
 
 use clap::Parser;
 use deunicode::deunicode;
-use promptly::prompt_default;
 use regex::Regex;
 use rsfs::DirEntry;
+use rsfs::FileType;
 use rsfs::GenFS;
 use rsfs::Metadata;
+use sha2::Digest;
+use sha2::Sha256;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use tempfile::TempDir;
-
-    #[test]
-    fn test_unixize_filename_str() {
-        let f = unixize_filename_str;
-        assert_eq!(f("verbatim"), "verbatim");
-        assert_eq!(f("__trim____"), "trim");
-        assert_eq!(f("__a___b___c__"), "a_b_c");
-        assert_eq!(f("  a   b   c  "), "a_b_c");
-        assert_eq!(f("a-b-c"), "a-b-c");
-        assert_eq!(
-            f("🤔😀😃😄😁😆😅emojis.txt"),
-            "thinking_grinning_smiley_smile_grin_laughing_sweat_smile_emojis.txt"
-        );
-        assert_eq!(f("Æneid"), "AEneid");
-        assert_eq!(f("étude"), "etude");
-        assert_eq!(f("北亰"), "Bei_Jing");
-        assert_eq!(f("げんまい茶"), "genmaiCha");
-        assert_eq!(f("🦄☣"), "unicorn_biohazard");
-        assert_eq!(f("Game (Not Pirated 😉).rar"), "Game_Not_Pirated_wink.rar");
-        assert_eq!(f("--fake-flag"), "fake-flag");
-        assert_eq!(f("Évidemment"), "Evidemment");
-        assert_eq!(f("àà_y_ü"), "aa_y_u");
+/// Clean up a string representing a filename, replacing
+/// unix-unfriendly characters (like spaces, parentheses, etc.) Duplicate
+/// markers like ` (1)` are turned into a collision-resolving number with
+/// `--normalize-markers`, dots in the stem are replaced with
+/// `--replace-stem-dots`, and the extension is
+/// rewritten according to `--lowercase-ext`, `--normalize-ext`, and
+/// `--ext-map`, as set up in `rules`. Directories have no extension, so what
+/// follows their last dot is kept, or with `--replace-stem-dots`, every dot
+/// is replaced. See the unit tests for examples.
+fn unixize_filename_str(fname: &str, kind: EntryKind, rules: &NamingRules) -> String {
+    let mut parts = FilenameParts::from_filename(&clean_filename_str(fname), rules);
+    if let Some(marked) = FilenameParts::from_duplicate(fname, rules) {
+        let unmarked = FilenameParts {
+            num: None,
+            ..marked
+        }
+        .merge();
+        let unmarked = FilenameParts::from_filename(&clean_filename_str(&unmarked), rules);
+        // Keep the marker as-is if the rest of the name is lost or already
+        // looks numbered
+        if !unmarked.stem.is_empty() && unmarked.num.is_none() {
+            parts = FilenameParts {
+                num: marked.num,
+                ..unmarked
+            };
+        }
     }
-
-    #[test]
-    fn test_resolve_collision() {
-        let fs = rsfs::disk::FS;
-        let root = TempDir::new().unwrap();
-        let root = root.path();
-        test_resolve_collision_fs(&fs, root);
-
-        let fs = rsfs::mem::FS::new();
-        let root = Path::new("/");
-        test_resolve_collision_fs(&fs, root);
+    if kind == EntryKind::Dir {
+        parts.normalize_dir_name(rules);
+    } else {
+        parts.normalize_stem(rules);
+        parts.normalize_ext(rules);
     }
+    parts.merge()
+}
 
-    fn test_resolve_collision_fs<FS: GenFS>(fs: &FS, root: &Path) {
-        // Helper function taking a collider filename returning a
-        // string representing the resolved collision
-        let f = |filename: &str| -> String {
-            let path = root.join(filename);
-            fs.create_file(&path).unwrap();
-
-            resolve_collision(fs, root, &path)
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string()
-        };
+/// Like `unixize_filename_str()`, but a bare `-N` marker, like in
+/// `name-1.ext`, is also turned into a collision-resolving number with
+/// `--normalize-markers` if `exists` says that the unmarked name `name.ext`
+/// is in the same directory. Otherwise, names like `chapter-1.md` are kept.
+fn unixize_name_in_dir<F: Fn(&OsStr) -> bool>(
+    fname: &str,
+    kind: EntryKind,
+    rules: &NamingRules,
+    exists: F,
+) -> String {
+    if let Some(marked) = FilenameParts::from_hyphen_marker(fname, rules) {
+        let unmarked = FilenameParts {
+            num: None,
+            ..marked
+        }
+        .merge();
+        if exists(OsStr::new(&unmarked)) {
+            let unixized = unixize_filename_str(&unmarked, kind, rules);
+            let parts = FilenameParts::from_filename(&unixized, rules);
+            // Like for other markers, keep the marker as-is if the rest of
+            // the name is lost or already looks numbered
+            if !parts.stem.is_empty() && parts.num.is_none() {
+                return FilenameParts {
+                    num: marked.num,
+                    ..parts
+                }
+                .merge();
+            }
+        }
+    }
+    unixize_filename_str(fname, kind, rules)
+}
 
-        assert_eq!(f("a"), "a_000");
-        assert_eq!(f("b_000"), "b_001");
-        assert_eq!(f("c.txt"), "c_000.txt");
-        assert_eq!(f("d_333.txt"), "d_334.txt");
-        assert_eq!(f("e_999.txt"), "e_1000.txt");
-        assert_eq!(f("e_1000.txt"), "e_1000_000.txt");
-        assert_eq!(f("z___222.txt"), "z___223.txt");
-        assert_eq!(f(".x._._._222.txt"), ".x._._._223.txt");
+/// Names that the file named `fname` may be a duplicate of, given that it is
+/// unixized to `new_name`: `new_name` itself, `new_name` without its
+/// collision-resolving number, and `fname` unixized without its duplicate
+/// marker, even if markers are not normalized
+fn duplicate_names(fname: &str, new_name: &str, rules: &NamingRules) -> Vec<String> {
+    let mut names = vec![new_name.to_string()];
+    let parts = FilenameParts::from_filename(new_name, rules);
+    if parts.num.is_some() {
+        names.push(FilenameParts { num: None, ..parts }.merge());
+    }
+    if let Some(marked) = FilenameParts::from_marker(fname, rules) {
+        let unmarked = FilenameParts {
+            num: None,
+            ..marked
+        }
+        .merge();
+        let unmarked = unixize_filename_str(&unmarked, EntryKind::File, rules);
+        if !names.contains(&unmarked) {
+            names.push(unmarked);
+        }
     }
+    names
 }
 
-/// Clean up a string representing a filename, replacing
-/// unix-unfriendly characters (like spaces, parentheses, etc.) See the
-/// unit tests for examples.
-fn unixize_filename_str(fname: &str) -> String {
+/// Replace the unix-unfriendly characters in a filename
+fn clean_filename_str(fname: &str) -> String {
     lazy_static! {
         static ref RE_INVAL_CHR: Regex = Regex::new("[^a-zA-Z0-9._-]").unwrap();
         static ref RE_UND_DUP: Regex = Regex::new("_+").unwrap();
@@ -115,6 +175,160 @@ fn unixize_filename_str(fname: &str) -> String {
     s.to_string()
 }
 
+/// Kind of a filesystem entry, determined without following symlinks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    /// FIFOs, sockets, and device nodes
+    Special,
+}
+
+impl EntryKind {
+    fn from_file_type<T: FileType>(file_type: T) -> Self {
+        if file_type.is_symlink() {
+            Self::Symlink
+        } else if file_type.is_dir() {
+            Self::Dir
+        } else if file_type.is_file() {
+            Self::File
+        } else {
+            Self::Special
+        }
+    }
+
+    /// Determine the kind of the entry at `path`, and whether it is a
+    /// directory that can be descended into. Symlinks are renamed themselves
+    /// and only descended into when following symlinks.
+    fn inspect<FS: GenFS>(
+        fs: &FS,
+        cwd: &Path,
+        path: &Path,
+        follow_symlinks: bool,
+    ) -> std::io::Result<(Self, bool)> {
+        let stat = fs.symlink_metadata(cwd.join(path))?;
+        let kind = Self::from_file_type(stat.file_type());
+        let is_dir = match kind {
+            Self::Dir => true,
+            Self::Symlink if follow_symlinks => fs
+                .metadata(cwd.join(path))
+                .map(|stat| stat.is_dir())
+                .unwrap_or(false),
+            _ => false,
+        };
+        Ok((kind, is_dir))
+    }
+
+    /// Like `inspect()`, for the entry `name` in the open directory `dir`
+    fn inspect_at<FS: FSExt>(
+        fs: &FS,
+        dir: &FS::Dir,
+        name: &OsStr,
+        follow_symlinks: bool,
+    ) -> std::io::Result<(Self, bool)> {
+        let kind = fs.kind_at(dir, name, false)?;
+        let is_dir = match kind {
+            Self::Dir => true,
+            Self::Symlink if follow_symlinks => fs
+                .kind_at(dir, name, true)
+                .map(|kind| kind == Self::Dir)
+                .unwrap_or(false),
+            _ => false,
+        };
+        Ok((kind, is_dir))
+    }
+}
+
+/// Returns `true` if an entry of kind `kind` at `path` should be renamed,
+/// according to the `--type` and `--special` flags. Special files are never
+/// selected by `--type`.
+fn kind_selected(path: &Path, kind: EntryKind, flags: &Flags) -> bool {
+    match (kind, flags.entry_type) {
+        (EntryKind::Special, Some(_)) => false,
+        (EntryKind::Special, None) => match flags.special {
+            SpecialPolicy::Warn => {
+                eprintln!("unf: warning: skipping special file '{}'", path.display());
+                false
+            }
+            SpecialPolicy::Skip => false,
+            SpecialPolicy::Rename => true,
+        },
+        (_, None) => true,
+        (EntryKind::File, Some(entry_type)) => entry_type == EntryType::File,
+        (EntryKind::Dir, Some(entry_type)) => entry_type == EntryType::Dir,
+        (EntryKind::Symlink, Some(entry_type)) => entry_type == EntryType::Symlink,
+    }
+}
+
+/// Pick the new name of the entry of kind `kind` at `path`, given the
+/// `names` taken in its directory: its unixized name, and that name with
+/// collisions resolved. Returns `None` if the entry keeps its name or is not
+/// selected by `--type` and `--special`.
+fn pick_new_name(
+    path: &Path,
+    kind: EntryKind,
+    flags: &Flags,
+    rules: &NamingRules,
+    names: &mut DirNames,
+) -> Option<(String, String)> {
+    let basename = path.file_name()?.to_string_lossy();
+    let new_basename = unixize_name_in_dir(&basename, kind, rules, |name| names.contains(name));
+    // Skip files that already have unix-friendly names
+    if basename == new_basename || !kind_selected(path, kind, flags) {
+        return None;
+    }
+    let resolved = names.resolve_collision(new_basename.clone(), rules);
+    Some((new_basename, resolved))
+}
+
+/// Whether to descend into a directory when following symlinks
+enum Descent {
+    /// Descend, with the file ID of the directory to add to the ancestors of
+    /// its children when following symlinks
+    Into(Option<FileId>),
+    /// Do not descend, since the directory is one of its own ancestors
+    Loop,
+}
+
+impl Descent {
+    /// Check whether descending into `dir` would loop back to one of its
+    /// `ancestors` through symlinks, warning if so
+    fn check<FS: FSExt>(
+        fs: &FS,
+        cwd: &Path,
+        dir: &Path,
+        follow_symlinks: bool,
+        ancestors: &[FileId],
+    ) -> io::Result<Self> {
+        if !follow_symlinks {
+            return Ok(Self::Into(None));
+        }
+        let id = fs.file_id(cwd.join(dir))?;
+        if ancestors.contains(&id) {
+            eprintln!(
+                "unf: warning: not descending into symlink loop '{}'",
+                dir.display()
+            );
+            return Ok(Self::Loop);
+        }
+        Ok(Self::Into(Some(id)))
+    }
+}
+
+/// Compute the SHA-256 hash of the content of the file at `path`
+fn content_hash<FS: GenFS>(fs: &FS, path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = fs.open_file(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finalize().to_vec()),
+            n => hasher.update(&buffer[..n]),
+        }
+    }
+}
+
 fn read_children_names<FS: GenFS>(fs: &FS, cwd: &Path, dir: &Path) -> Result<BTreeSet<OsString>> {
     let children_names = fs
         .read_dir(cwd.join(dir))?
@@ -123,84 +337,665 @@ fn read_children_names<FS: GenFS>(fs: &FS, cwd: &Path, dir: &Path) -> Result<BTr
     Ok(children_names)
 }
 
-/// Like `unixize_path()`, but only operate on children of `dir`
-fn unixize_children<FS: GenFS>(fs: &FS, cwd: &Path, dir: &Path, flags: Flags) -> Result<()> {
-    for file_name in read_children_names(fs, cwd, dir)? {
-        let path = dir.join(file_name);
-        unixize_path(fs, cwd, &path, flags)?;
-    }
-    Ok(())
+/// State of a single run of `unf` over a filesystem
+struct Unixizer<'a, FS: FSExt> {
+    fs: &'a FS,
+    cwd: &'a Path,
+    flags: &'a Flags,
+
+    /// How filenames are split and rewritten, built from `flags`
+    rules: NamingRules,
+
+    /// Identities of the directories currently being descended into, used to
+    /// detect symlink cycles when following symlinks
+    ancestors: Vec<FileId>,
+
+    /// Handles of the directories currently being descended into, innermost
+    /// last. Entries are renamed relative to these, so that a directory that
+    /// is swapped for a symlink cannot redirect renames.
+    dirs: Vec<FS::Dir>,
+
+    /// Paths given on the command line. Parents of renamed entries are only
+    /// opened by path down to these, and one component at a time below them.
+    roots: Vec<PathBuf>,
+
+    /// Renames performed so far, in order
+    renames: Vec<Rename>,
+
+    /// Git working tree information, if renaming through git
+    git: Option<Git>,
+
+    /// Names in the directories being processed, kept up to date as entries
+    /// are renamed
+    dir_index: DirIndex,
+
+    /// How to confirm descending into directories and renaming
+    confirm: Confirm,
+
+    /// Set when the user chose to skip the rest of the current directory
+    skip_dir: bool,
+
+    /// Set when the user chose to quit, or when a signal was caught
+    quit: bool,
+
+    /// Number of planned renames left undone after a signal was caught
+    not_done: usize,
+
+    /// Content hashes of the files compared with `--on-duplicate`, so that a
+    /// file that many duplicates collide with is only read once
+    hashes: HashMap<FileId, Vec<u8>>,
 }
 
-/// Unixize the filename(s) specified by a path, according to the
-/// supplied arguments
-fn unixize_path<FS: GenFS>(fs: &FS, cwd: &Path, path: &Path, flags: Flags) -> Result<()> {
-    let parent = path.parent().unwrap_or(cwd);
-    let basename = &path.file_name().map(OsStr::to_string_lossy);
-    let basename = match basename {
-        Some(s) => s,
-        // If the path has no basename (for example, if it's `.` or `..`), only
-        // unixize children
-        None => return unixize_children(fs, cwd, path, flags),
-    };
-    let new_basename = unixize_filename_str(basename);
-
-    let stat = fs.metadata(cwd.join(path))?;
-    let is_dir = stat.is_dir();
-    let should_prompt = !flags.force && !flags.dry_run;
-
-    // Determine whether to recurse, possibly by prompting the user
-    let recurse = flags.recursive
-        && is_dir
-        && (!should_prompt || {
-            let msg = format!("descend into directory '{}'?", path.display());
-            prompt_default(msg, false)?
+impl<'a, FS: FSExt> Unixizer<'a, FS> {
+    fn new(fs: &'a FS, cwd: &'a Path, flags: &'a Flags, git: Option<Git>) -> Self {
+        Self {
+            fs,
+            cwd,
+            flags,
+            rules: NamingRules::from_flags(flags),
+            ancestors: Vec::new(),
+            dirs: Vec::new(),
+            roots: Vec::new(),
+            renames: Vec::new(),
+            git,
+            dir_index: DirIndex::default(),
+            confirm: if flags.force || flags.dry_run || flags.tui {
+                Confirm::Always
+            } else {
+                Confirm::Prompt(Prompter::new())
+            },
+            skip_dir: false,
+            quit: false,
+            not_done: 0,
+            hashes: HashMap::new(),
+        }
+    }
+
+    /// Like `unixize_path()`, but only operate on children of `dir`
+    fn unixize_children(&mut self, dir: &Path) -> Result<()> {
+        let id = match Descent::check(
+            self.fs,
+            self.cwd,
+            dir,
+            self.flags.follow_symlinks,
+            &self.ancestors,
+        )? {
+            Descent::Into(id) => id,
+            Descent::Loop => return Ok(()),
+        };
+
+        let handle = match (self.dirs.last(), dir.file_name()) {
+            (Some(parent), Some(name)) => {
+                self.fs
+                    .open_dir_at(parent, name, self.flags.follow_symlinks)
+            }
+            _ => self.fs.open_dir(self.cwd.join(dir)),
+        }
+        .map_err(|err| format!("cannot open directory '{}': {}", dir.display(), err))?;
+
+        // Children are listed from the opened directory, so that it is the
+        // one they are inspected and renamed in even if it is swapped
+        let children_names = self
+            .fs
+            .read_dir_at(&handle)
+            .map(|names| names.into_iter().collect::<BTreeSet<OsString>>());
+        self.ancestors.extend(id);
+        self.dirs.push(handle);
+        let result = children_names
+            .map_err(Into::into)
+            .and_then(|children_names| {
+                let names = DirNames::new(children_names.iter().cloned());
+                self.dir_index.insert(self.cwd, dir, names);
+                for file_name in children_names {
+                    let path = dir.join(file_name);
+                    self.unixize_path(&path)?;
+                    if self.quit {
+                        break;
+                    }
+                    if self.skip_dir {
+                        self.skip_dir = false;
+                        break;
+                    }
+                }
+                Ok(())
+            });
+        self.dir_index.forget(self.cwd, dir);
+        self.dirs.pop();
+        if id.is_some() {
+            self.ancestors.pop();
+        }
+        result
+    }
+
+    /// Unixize the filename(s) specified by a path, according to the
+    /// supplied arguments
+    fn unixize_path(&mut self, path: &Path) -> Result<()> {
+        let fs = self.fs;
+        let cwd = self.cwd;
+        let flags = self.flags;
+
+        // Stop between renames after SIGINT or SIGTERM
+        if signals::caught().is_some() {
+            self.quit = true;
+            return Ok(());
+        }
+
+        let parent = path.parent().unwrap_or(cwd);
+        let basename = &path.file_name().map(OsStr::to_string_lossy);
+        let basename = match basename {
+            Some(s) => s,
+            // If the path has no basename (for example, if it's `.` or `..`), only
+            // unixize children
+            None => return self.unixize_children(path),
+        };
+        let (kind, is_dir) = match (self.dirs.last(), path.file_name()) {
+            (Some(dir), Some(name)) => EntryKind::inspect_at(fs, dir, name, flags.follow_symlinks)?,
+            _ => EntryKind::inspect(fs, cwd, path, flags.follow_symlinks)?,
+        };
+
+        // Determine whether to recurse, possibly by prompting the user
+        let recurse = flags.recursive && is_dir && {
+            let question = format!("descend into directory '{}'?", path.display());
+            self.ask(&question, false)? == Answer::Yes
+        };
+        if self.skip_dir || self.quit {
+            return Ok(());
+        }
+
+        if recurse {
+            self.unixize_children(path)?;
+        }
+
+        // Pick the new name after recursive handling, because unix-friendly
+        // directory names might have non-unix-friendly filenames inside
+        let names = self.dir_index.get(fs, cwd, parent)?;
+        let (new_basename, resolved) = match pick_new_name(path, kind, flags, &self.rules, names) {
+            Some(new_names) => new_names,
+            None => return Ok(()),
+        };
+
+        if flags.merge_dirs && kind == EntryKind::Dir {
+            let target = parent.join(&new_basename);
+            if self.is_other_dir(path, &target)? {
+                return self.merge_dirs(path, &target);
+            }
+        }
+        if let Some(action) = flags.on_duplicate.filter(|_| kind == EntryKind::File) {
+            for name in duplicate_names(basename, &new_basename, &self.rules) {
+                let target = parent.join(name);
+                if self.is_duplicate(path, &target)? {
+                    return self.remove_duplicate(path, &target, action);
+                }
+            }
+        }
+
+        let mut new_basename = resolved;
+        let rename_prefix = if flags.dry_run {
+            "would rename"
+        } else {
+            "rename"
+        };
+        let mut prompted = false;
+        loop {
+            let answer = if let Confirm::Always = self.confirm {
+                Answer::Yes
+            } else {
+                // Interactively prompt whether to rename the file, skipping
+                // if the user says no
+                prompted = true;
+                let question = format!(
+                    "{} '{}' -> '{}'?",
+                    rename_prefix,
+                    path.display(),
+                    parent.join(&new_basename).display()
+                );
+                self.ask(&question, true)?
+            };
+
+            match answer {
+                Answer::Yes => break,
+                Answer::Edit => match self.edit_name(&new_basename)? {
+                    Some(edited) if edited == basename.as_ref() => return Ok(()),
+                    Some(edited) => {
+                        new_basename = self
+                            .dir_index
+                            .get(fs, cwd, parent)?
+                            .resolve_collision(edited, &self.rules);
+                    }
+                    None if self.quit => return Ok(()),
+                    None => continue,
+                },
+                _ => return Ok(()),
+            }
+        }
+
+        let new_basename = self.rename_entry(path, new_basename)?;
+        let new_path = parent.join(&new_basename);
+        // Log renames that were not prompted for, unless a script is printed
+        // instead
+        if !prompted && flags.emit_script.is_none() {
+            println!(
+                "{} '{}' -> '{}'",
+                rename_prefix,
+                path.display(),
+                new_path.display()
+            );
+        }
+        self.dir_index.get(fs, cwd, parent)?.rename(
+            path.file_name().unwrap(),
+            OsStr::new(&new_basename),
+            &self.rules,
+        );
+        self.renames.push(Rename {
+            from: path.to_path_buf(),
+            to: new_path,
         });
+        Ok(())
+    }
+
+    /// Returns `true` if `target` is a different regular file than the one at
+    /// `path`, with the same content. Sizes are compared before hashes, and
+    /// hashes are cached by file identity.
+    fn is_duplicate(&mut self, path: &Path, target: &Path) -> Result<bool> {
+        let (path, target) = (self.cwd.join(path), self.cwd.join(target));
+        let target_stat = match self.fs.symlink_metadata(&target) {
+            Ok(stat) if stat.is_file() => stat,
+            Ok(_) => return Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        if self.fs.symlink_metadata(&path)?.len() != target_stat.len() {
+            return Ok(false);
+        }
+        let (id, target_id) = (self.fs.file_id(&path)?, self.fs.file_id(&target)?);
+        if id == target_id {
+            return Ok(false);
+        }
 
-    if recurse {
-        unixize_children(fs, cwd, path, flags)?;
+        for (id, path) in [(id, &path), (target_id, &target)] {
+            if !self.hashes.contains_key(&id) {
+                let hash = content_hash(self.fs, path)?;
+                self.hashes.insert(id, hash);
+            }
+        }
+        Ok(self.hashes[&id] == self.hashes[&target_id])
     }
 
-    // Skip files that already have unix-friendly names; this is done
-    // after recursive handling because unix-friendly directory names
-    // might have non-unix-friendly filenames inside
-    if basename == &new_basename {
-        return Ok(());
+    /// Returns `true` if `target` is a different directory than the one at
+    /// `path`, not counting symlinks to directories
+    fn is_other_dir(&self, path: &Path, target: &Path) -> Result<bool> {
+        let (path, target) = (self.cwd.join(path), self.cwd.join(target));
+        match self.fs.symlink_metadata(&target) {
+            Ok(stat) if stat.is_dir() => {}
+            Ok(_) => return Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+        Ok(self.fs.file_id(&path)? != self.fs.file_id(&target)?)
     }
 
-    let new_path = parent.join(new_basename);
-    let new_path = resolve_collision(fs, cwd, &new_path);
-    let rename_prefix = if flags.dry_run {
-        "would rename"
-    } else {
-        "rename"
-    };
-    let msg = format!(
-        "{} '{}' -> '{}'",
-        rename_prefix,
-        path.display(),
-        new_path.display()
-    );
-    if should_prompt {
-        // Interactively prompt whether to rename the file, skipping
-        // if the user says no
-        let msg = format!("{}?", msg);
-        if !prompt_default(msg, false)? {
+    /// Merge the directory at `path` into the directory `target`, possibly by
+    /// prompting the user
+    fn merge_dirs(&mut self, path: &Path, target: &Path) -> Result<()> {
+        let prefix = if self.flags.dry_run {
+            "would merge"
+        } else {
+            "merge"
+        };
+        let message = format!("{} '{}' -> '{}'", prefix, path.display(), target.display());
+        if let Confirm::Always = self.confirm {
+            println!("{}", message);
+        } else if self.ask(&format!("{}?", message), false)? != Answer::Yes {
             return Ok(());
         }
-    } else {
-        // Log rename non-interactively
-        println!("{}", msg);
+
+        self.merge_into(path, target)?;
+        let parent = path.parent().unwrap_or(self.cwd);
+        self.dir_index
+            .get(self.fs, self.cwd, parent)?
+            .remove(path.file_name().unwrap(), &self.rules);
+        Ok(())
     }
 
-    fs.rename(cwd.join(path), cwd.join(new_path))?;
-    Ok(())
+    /// Move the entries of the directory `dir` into the directory `target`,
+    /// and remove `dir` once it is empty. Directories whose names are taken
+    /// by directories are merged in turn, and other entries whose names are
+    /// taken get a collision-resolving number, unless they are duplicates
+    /// handled by `--on-duplicate`. Stops after SIGINT or SIGTERM.
+    fn merge_into(&mut self, dir: &Path, target: &Path) -> Result<()> {
+        let fs = self.fs;
+        let cwd = self.cwd;
+        let rename_prefix = if self.flags.dry_run {
+            "would rename"
+        } else {
+            "rename"
+        };
+
+        for name in read_children_names(fs, cwd, dir)? {
+            if signals::caught().is_some() {
+                self.quit = true;
+                return Ok(());
+            }
+
+            let path = dir.join(&name);
+            let taken = target.join(&name);
+            let (kind, _) = EntryKind::inspect(fs, cwd, &path, false)?;
+            if kind == EntryKind::Dir && self.is_other_dir(&path, &taken)? {
+                self.merge_into(&path, &taken)?;
+                if self.quit {
+                    return Ok(());
+                }
+                continue;
+            }
+            if let Some(action) = self.flags.on_duplicate {
+                if kind == EntryKind::File && self.is_duplicate(&path, &taken)? {
+                    self.remove_duplicate(&path, &taken, action)?;
+                    continue;
+                }
+            }
+
+            // Names that are not valid UTF-8 are kept, and fail to move if
+            // they are taken
+            let names = self.dir_index.get(fs, cwd, target)?;
+            let new_name = match name.to_str() {
+                Some(name) => {
+                    OsString::from(names.resolve_collision(name.to_string(), &self.rules))
+                }
+                None => name.clone(),
+            };
+            names.insert(new_name.clone());
+            let new_path = target.join(&new_name);
+            self.rename(&path, &new_path)?;
+            println!(
+                "{} '{}' -> '{}'",
+                rename_prefix,
+                path.display(),
+                new_path.display()
+            );
+            self.renames.push(Rename {
+                from: path,
+                to: new_path,
+            });
+        }
+
+        self.dir_index.forget(cwd, dir);
+        self.dir_index.forget(cwd, target);
+        fs.remove_dir(cwd.join(dir))
+            .map_err(|err| format!("cannot remove '{}': {}", dir.display(), err))?;
+        Ok(())
+    }
+
+    /// Remove the file at `path`, or move it to the trash, since `target`
+    /// has the same content, possibly by prompting the user
+    fn remove_duplicate(
+        &mut self,
+        path: &Path,
+        target: &Path,
+        action: DuplicateAction,
+    ) -> Result<()> {
+        let verb = match action {
+            DuplicateAction::Remove => "remove",
+            DuplicateAction::Trash => "trash",
+        };
+        let prefix = if self.flags.dry_run {
+            format!("would {}", verb)
+        } else {
+            verb.to_string()
+        };
+        let message = format!(
+            "{} duplicate '{}' of '{}'",
+            prefix,
+            path.display(),
+            target.display()
+        );
+        if let Confirm::Always = self.confirm {
+            println!("{}", message);
+        } else if self.ask(&format!("{}?", message), false)? != Answer::Yes {
+            return Ok(());
+        }
+
+        // Files are removed relative to their parent opened like for
+        // renames, so that a directory swapped for a symlink cannot redirect
+        // the removal. The trash can only be reached by path.
+        let result = match action {
+            DuplicateAction::Remove => self
+                .open_parent(path)
+                .and_then(|(dir, name)| Ok(self.fs.remove_file_at(&dir, name)?)),
+            DuplicateAction::Trash => self.fs.trash(self.cwd.join(path)).map_err(Into::into),
+        };
+        result.map_err(|err| format!("cannot {} '{}': {}", verb, path.display(), err))?;
+        let parent = path.parent().unwrap_or(self.cwd);
+        self.dir_index
+            .get(self.fs, self.cwd, parent)?
+            .remove(path.file_name().unwrap(), &self.rules);
+        Ok(())
+    }
+
+    /// Confirm the action described by `question`, remembering answers that
+    /// stop processing the current directory or the whole run
+    fn ask(&mut self, question: &str, editable: bool) -> Result<Answer> {
+        let answer = self.confirm.ask(question, editable)?;
+        match answer {
+            Answer::SkipDir => self.skip_dir = true,
+            Answer::Quit => self.quit = true,
+            _ => (),
+        }
+        Ok(answer)
+    }
+
+    /// Let the user edit the new filename `name`, returning `None` if the
+    /// edited name is invalid. Quits if input ended.
+    fn edit_name(&mut self, name: &str) -> Result<Option<String>> {
+        let edited = match &mut self.confirm {
+            Confirm::Prompt(prompter) => prompter.edit(name)?,
+            _ => return Ok(None),
+        };
+        match edited {
+            Some(edited) if is_valid_filename(&edited) => Ok(Some(edited)),
+            Some(edited) => {
+                eprintln!("unf: invalid filename '{}'", edited);
+                Ok(None)
+            }
+            None => {
+                self.quit = true;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Rename the entry at `path` to `new_basename` in the same directory,
+    /// returning the name it got. Unless renaming through git, the entry is
+    /// renamed relative to the handle of the directory being descended into,
+    /// and if another process took the name since collisions were resolved,
+    /// the next free name is used instead.
+    fn rename_entry(&mut self, path: &Path, mut new_basename: String) -> Result<String> {
+        let parent = path.parent().unwrap_or(self.cwd);
+        if let Some(git) = &mut self.git {
+            if git.is_tracked(self.cwd, path)? {
+                git.mv(self.cwd, path, &parent.join(&new_basename))?;
+                return Ok(new_basename);
+            }
+        }
+
+        let opened;
+        let dir = match self.dirs.last() {
+            Some(dir) => dir,
+            None => {
+                opened = self.fs.open_dir(self.cwd.join(parent))?;
+                &opened
+            }
+        };
+        let name = path.file_name().unwrap();
+        loop {
+            match self.fs.rename_at(dir, name, dir, OsStr::new(&new_basename)) {
+                Ok(()) => return Ok(new_basename),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    let names = self.dir_index.get(self.fs, self.cwd, parent)?;
+                    names.insert(new_basename.clone().into());
+                    let next = names.resolve_collision(new_basename.clone(), &self.rules);
+                    eprintln!(
+                        "unf: warning: '{}' was created by another process, using '{}' instead",
+                        parent.join(&new_basename).display(),
+                        next
+                    );
+                    new_basename = next;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Rename `from` to `to`, through the git index if `from` is tracked by
+    /// git and using `--git`. Fails instead of replacing an existing entry.
+    /// Their parents are opened with `open_parent()`, so that a directory
+    /// swapped for a symlink cannot redirect the rename.
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        if let Some(git) = &mut self.git {
+            if git.is_tracked(self.cwd, from)? {
+                return git.mv(self.cwd, from, to);
+            }
+        }
+        let (from_dir, from_name) = self.open_parent(from)?;
+        let (to_dir, to_name) = self.open_parent(to)?;
+        self.fs.rename_at(&from_dir, from_name, &to_dir, to_name)?;
+        Ok(())
+    }
+
+    /// Open the parent of `path`, returning it along with the final component
+    /// of `path`. The longest path given on the command line, or parent of
+    /// one, that contains the parent is opened by path, and the rest of the
+    /// parent one component at a time.
+    fn open_parent<'p>(&self, path: &'p Path) -> Result<(FS::Dir, &'p OsStr)> {
+        let (parent, name) = fs_ext::split_parent(path)?;
+        let base = self
+            .roots
+            .iter()
+            .flat_map(|root| [Some(root.as_path()), root.parent()])
+            .flatten()
+            .filter(|base| parent.starts_with(base))
+            .max_by_key(|base| base.components().count())
+            .unwrap_or(Path::new(""));
+        let dir = fs_ext::open_dir_below(
+            self.fs,
+            &self.cwd.join(base),
+            parent.strip_prefix(base)?,
+            self.flags.follow_symlinks,
+        )?;
+        Ok((dir, name))
+    }
+
+    /// Perform the renames in `plan`, printing each of them, and stopping
+    /// between renames after SIGINT or SIGTERM. With `--transactional`, the
+    /// renames are rolled back if one of them fails or after a signal.
+    fn apply_plan(&mut self, plan: Vec<Rename>) -> Result<()> {
+        let rename_prefix = if self.flags.dry_run {
+            "would rename"
+        } else {
+            "rename"
+        };
+        let transactional = self.flags.transactional && !self.flags.dry_run;
+
+        let start = self.renames.len();
+        let count = plan.len();
+        for (i, mut rename) in plan.into_iter().enumerate() {
+            if let Some(signal) = signals::caught() {
+                if transactional {
+                    let err = format!("interrupted by {}", signals::name(signal));
+                    return self.roll_back(start, err.into());
+                }
+                self.quit = true;
+                self.not_done = count - i;
+                break;
+            }
+            // If another process took the planned name, use the next free
+            // one instead
+            let result = loop {
+                match self.rename(&rename.from, &rename.to) {
+                    Err(err) if is_already_exists(&*err) => {
+                        let name = rename.to.file_name().unwrap().to_string_lossy();
+                        let next = inc_filename_num(&name, &self.rules);
+                        eprintln!(
+                            "unf: warning: '{}' was created by another process, using '{}' instead",
+                            rename.to.display(),
+                            next
+                        );
+                        rename.to.set_file_name(next);
+                    }
+                    result => break result,
+                }
+            };
+            match result {
+                Ok(()) => {
+                    if self.flags.emit_script.is_none() {
+                        println!(
+                            "{} '{}' -> '{}'",
+                            rename_prefix,
+                            rename.from.display(),
+                            rename.to.display()
+                        );
+                    }
+                    self.renames.push(rename);
+                }
+                Err(err) if transactional => return self.roll_back(start, err),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo the renames performed since the first `start` ones in reverse
+    /// order, after they were stopped by `err`
+    fn roll_back(&mut self, start: usize, err: Box<dyn std::error::Error>) -> Result<()> {
+        let count = self.renames.len() - start;
+        while self.renames.len() > start {
+            let rename = self.renames.pop().unwrap();
+            println!(
+                "roll back '{}' -> '{}'",
+                rename.to.display(),
+                rename.from.display()
+            );
+            if let Err(rollback_err) = self.rename(&rename.to, &rename.from) {
+                self.renames.push(rename);
+                return Err(format!(
+                    "{}; rolling back failed: {}; {} renames were not rolled back",
+                    err,
+                    rollback_err,
+                    self.renames.len() - start
+                )
+                .into());
+            }
+        }
+        Err(format!("{}; rolled back {} renames", err, count).into())
+    }
+
+    fn unixize_paths(&mut self, paths: &[PathBuf]) -> Result<()> {
+        for path in paths {
+            self.unixize_path(path)?;
+
+            // Skipping the rest of the directory skips the remaining paths
+            if self.quit || self.skip_dir {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns `true` if `err` is an I/O error because an entry already exists
+fn is_already_exists(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::AlreadyExists)
+}
+
+/// Returns `true` if `name` can be used as the name of a directory entry
+fn is_valid_filename(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
 }
 
 /// Split, modify, and re-merge filename to increment the
 /// collision-resolving number, or create it if non-existent
-fn inc_filename_num(filename: &str) -> String {
-    let FilenameParts { stem, num, ext } = FilenameParts::from_filename(filename);
+fn inc_filename_num(filename: &str, rules: &NamingRules) -> String {
+    let FilenameParts { stem, num, ext } = FilenameParts::from_filename(filename, rules);
     let num = match num {
         Some(val) => Some(val + 1),
         None => Some(0),
@@ -208,44 +1003,96 @@ fn inc_filename_num(filename: &str) -> String {
     FilenameParts { stem, num, ext }.merge()
 }
 
-/// Check if the target path can be written to without clobbering an
-/// existing file. If it can't, change it to a unique name. Note that
-/// this function requires that the filename is non-empty and valid
-/// UTF-8.
-fn resolve_collision<FS: GenFS>(fs: &FS, cwd: &Path, path: &Path) -> PathBuf {
-    if path_exists(fs, cwd, path) {
-        let filename = path
-            .file_name()
-            .expect("filename is empty")
-            .to_str()
-            .expect("filename is not valid UTF-8");
-        let filename = inc_filename_num(filename);
-        let path = path.with_file_name(filename);
-
-        // Recursively resolve the new filename. This is how the
-        // collision-resolving number is incremented.
-        resolve_collision(fs, cwd, &path)
+/// Unixize `paths` on the filesystem `fs`, renaming tracked files through
+/// `git` if given, and return the renames performed
+fn run<FS: FSExt>(
+    fs: &FS,
+    cwd: &Path,
+    paths: &[PathBuf],
+    flags: &Flags,
+    git: Option<Git>,
+) -> Result<Vec<Rename>> {
+    let mut unixizer = Unixizer::new(fs, cwd, flags, git);
+    unixizer.roots = paths.to_vec();
+    let plans = flags.jobs.is_some() || flags.transactional;
+    if plans && !flags.force && !flags.dry_run && !flags.tui {
+        let flag = if flags.jobs.is_some() {
+            "--jobs"
+        } else {
+            "--transactional"
+        };
+        return Err(format!("{} requires --force, --dry-run, or --tui", flag).into());
+    }
+
+    if plans || flags.tui {
+        // Plan, in a thread pool if requested, then rename sequentially
+        let planner = Planner::new(fs, cwd, flags);
+        let plan = match flags.jobs {
+            Some(jobs) => {
+                let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+                pool.install(|| planner.plan_paths(paths))?
+            }
+            None => planner.plan_paths(paths)?,
+        };
+        let plan = if flags.tui {
+            tui::review(fs, cwd, &unixizer.rules, plan)?
+        } else {
+            plan
+        };
+        unixizer.apply_plan(plan)?;
     } else {
-        // File does not exist; we're done!
-        path.to_path_buf()
+        unixizer.unixize_paths(paths)?;
+    }
+
+    if let Some(signal) = signals::caught() {
+        // Later steps are skipped, since the renames are incomplete
+        let not_done = if plans || flags.tui {
+            format!("{} planned renames were not done", unixizer.not_done)
+        } else {
+            "the remaining entries were not processed".to_string()
+        };
+        return Err(format!(
+            "interrupted by {} after {} renames; {}",
+            signals::name(signal),
+            unixizer.renames.len(),
+            not_done
+        )
+        .into());
     }
-}
 
-/// Returns `true` if the path points at an existing entity.
-fn path_exists<FS, P1, P2>(fs: &FS, cwd: P1, path: P2) -> bool
-where
-    FS: GenFS,
-    P1: AsRef<Path>,
-    P2: AsRef<Path>,
-{
-    let cwd = cwd.as_ref();
-    let path = path.as_ref();
-    fs.metadata(cwd.join(path)).is_ok()
+    if flags.update_symlinks {
+        symlinks::update_symlinks(fs, cwd, paths, &unixizer.renames, flags)?;
+    }
+    if !flags.rewrite_refs.is_empty() {
+        refs::rewrite_refs(fs, cwd, paths, &unixizer.renames, flags)?;
+    }
+    Ok(unixizer.renames)
 }
 
-fn unixize_paths<FS: GenFS>(fs: &FS, cwd: &Path, paths: &[PathBuf], flags: Flags) -> Result<()> {
-    for path in paths {
-        unixize_path(fs, cwd, path, flags)?;
+/// Unixize `paths` relative to `cwd` on the real filesystem, or on an overlay
+/// over it if using `--dry-run` or `--emit-script`
+fn unixize(cwd: &Path, paths: &[PathBuf], flags: &Flags) -> Result<()> {
+    if let Some(format) = flags.emit_script {
+        // Plan like a dry run, then print the renames as a script
+        let flags = Flags {
+            dry_run: true,
+            ..flags.clone()
+        };
+        let fs = overlay_fs::FS::default();
+        let renames = run(&fs, cwd, paths, &flags, None)?;
+        return script::emit(format, &renames, flags.undo_script.as_deref());
+    }
+
+    if flags.dry_run {
+        // If using `--dry-run`, use an overlay over the real filesystem that
+        // records renames in memory instead of performing them, so that
+        // collisions are resolved the same way as in a real run
+        let fs = overlay_fs::FS::default();
+        run(&fs, cwd, paths, flags, None)?;
+    } else {
+        let fs = rsfs::disk::FS;
+        let git = flags.git.then(Git::default);
+        run(&fs, cwd, paths, flags, git)?;
     }
     Ok(())
 }
@@ -254,15 +1101,20 @@ fn unixize_paths<FS: GenFS>(fs: &FS, cwd: &Path, paths: &[PathBuf], flags: Flags
 fn main_opts(opts: Opts) -> Result<()> {
     let cwd = std::env::current_dir()?;
 
-    if opts.flags.dry_run {
-        // If using `--dry-run`, load the file tree into an in-memory filesystem
-        // and use that instead of the real filesystem. This is required for the
-        // collision handling to work.
-        let fs = mem_fs::load(&opts.paths)?;
-        unixize_paths(&fs, &cwd, &opts.paths, opts.flags)
-    } else {
-        let fs = rsfs::disk::FS;
-        unixize_paths(&fs, &cwd, &opts.paths, opts.flags)
+    match opts.command {
+        #[cfg(all(feature = "watch", target_os = "linux"))]
+        Some(Command::Watch(watch_opts)) => watch::watch(&cwd, &watch_opts),
+        Some(Command::Archive(archive_opts)) => archive::rewrite(&cwd, &archive_opts),
+        Some(Command::Extract(extract_opts)) => extract::extract(&cwd, &extract_opts),
+        Some(Command::TarFilter) => archive::filter_tar(),
+        Some(Command::Apply(apply_opts)) => {
+            signals::catch_signals()?;
+            apply::apply(&cwd, &apply_opts)
+        }
+        None => {
+            signals::catch_signals()?;
+            unixize(&cwd, &opts.paths, &opts.flags)
+        }
     }
 }
 
@@ -275,6 +1127,250 @@ fn try_main() -> Result<()> {
 fn main() {
     if let Err(err) = try_main() {
         eprintln!("unf: error: {}", err);
-        std::process::exit(1);
+        // Exit with a distinct status if stopped by a signal
+        let status = signals::caught().map_or(1, signals::exit_status);
+        std::process::exit(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use rsfs::unix_ext::GenFSExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_unixize_filename_str() {
+        let rules = NamingRules::default();
+        let f = |fname| unixize_filename_str(fname, EntryKind::File, &rules);
+        assert_eq!(f("verbatim"), "verbatim");
+        assert_eq!(f("__trim____"), "trim");
+        assert_eq!(f("__a___b___c__"), "a_b_c");
+        assert_eq!(f("  a   b   c  "), "a_b_c");
+        assert_eq!(f("a-b-c"), "a-b-c");
+        assert_eq!(
+            f("🤔😀😃😄😁😆😅emojis.txt"),
+            "thinking_grinning_smiley_smile_grin_laughing_sweat_smile_emojis.txt"
+        );
+        assert_eq!(f("Æneid"), "AEneid");
+        assert_eq!(f("étude"), "etude");
+        assert_eq!(f("北亰"), "Bei_Jing");
+        assert_eq!(f("げんまい茶"), "genmaiCha");
+        assert_eq!(f("🦄☣"), "unicorn_biohazard");
+        assert_eq!(f("Game (Not Pirated 😉).rar"), "Game_Not_Pirated_wink.rar");
+        assert_eq!(f("--fake-flag"), "fake-flag");
+        assert_eq!(f("Évidemment"), "Evidemment");
+        assert_eq!(f("àà_y_ü"), "aa_y_u");
+        assert_eq!(f("IMG (1).jpg"), "IMG_1.jpg");
+        assert_eq!(f("Report - Copy.docx"), "Report_-_Copy.docx");
+        assert_eq!(f("chapter-1.md"), "chapter-1.md");
+
+        // Extension rules only apply to files
+        let rules = NamingRules::from_flags(&Flags::parse_from(["unf", "--normalize-ext"]));
+        let f = |fname, kind| unixize_filename_str(fname, kind, &rules);
+        assert_eq!(f("Photos 2020.JPEG", EntryKind::File), "Photos_2020.jpg");
+        assert_eq!(f("Photos 2020.JPEG", EntryKind::Dir), "Photos_2020.JPEG");
+        assert_eq!(f("Show.S01.720p.WEB", EntryKind::Dir), "Show.S01.720p.WEB");
+
+        let flags = Flags::parse_from(["unf", "--lowercase-ext", "--replace-stem-dots"]);
+        let rules = NamingRules::from_flags(&flags);
+        let f = |fname, kind| unixize_filename_str(fname, kind, &rules);
+        assert_eq!(f("Show.S01.720p.WEB", EntryKind::File), "Show_S01_720p.web");
+        assert_eq!(f("Show.S01.720p.WEB", EntryKind::Dir), "Show_S01_720p_WEB");
+
+        // Duplicate markers
+        let rules = NamingRules::from_flags(&Flags::parse_from(["unf", "--normalize-markers"]));
+        let f = |fname| unixize_filename_str(fname, EntryKind::File, &rules);
+        assert_eq!(f("IMG (1).jpg"), "IMG_000.jpg");
+        assert_eq!(f("IMG(2).jpg"), "IMG_001.jpg");
+        assert_eq!(f("Report - Copy.docx"), "Report_000.docx");
+        assert_eq!(f("Report - Copy (3).docx"), "Report_002.docx");
+        assert_eq!(f("notes copy 2.txt"), "notes_001.txt");
+        assert_eq!(f("setup-1.exe"), "setup-1.exe");
+        assert_eq!(f("lecture-1.pdf"), "lecture-1.pdf");
+
+        // Bare `-N` markers, only next to the unmarked name
+        let f = |fname| {
+            let exists = |name: &OsStr| ["setup.exe", "a b.txt"].map(OsStr::new).contains(&name);
+            unixize_name_in_dir(fname, EntryKind::File, &rules, exists)
+        };
+        assert_eq!(f("setup-1.exe"), "setup_000.exe");
+        assert_eq!(f("setup-3.exe"), "setup_002.exe");
+        assert_eq!(f("a b-1.txt"), "a_b_000.txt");
+        assert_eq!(f("lecture-1.pdf"), "lecture-1.pdf");
+        assert_eq!(f("IMG (1).jpg"), "IMG_000.jpg");
+        assert_eq!(f("Mon Film (2).tar"), "Mon_Film_001.tar");
+        assert_eq!(f("2021-10.csv"), "2021-10.csv");
+        assert_eq!(f("hard copy.pdf"), "hard_copy.pdf");
+        assert_eq!(f("(1).txt"), "1.txt");
+        assert_eq!(f("a_000 (1).txt"), "a_000_1.txt");
+        assert_eq!(f("backup (1).tar.gz"), "backup_000.tar.gz");
+    }
+
+    #[test]
+    fn test_resolve_collision() {
+        let fs = rsfs::disk::FS;
+        let root = TempDir::new().unwrap();
+        let root = root.path();
+        test_resolve_collision_fs(&fs, root);
+
+        let fs = rsfs::mem::FS::new();
+        let root = Path::new("/");
+        test_resolve_collision_fs(&fs, root);
+    }
+
+    fn test_resolve_collision_fs<FS: GenFS>(fs: &FS, root: &Path) {
+        // Helper function taking a collider filename returning a
+        // string representing the resolved collision
+        let f = |filename: &str| -> String {
+            fs.create_file(root.join(filename)).unwrap();
+
+            DirIndex::default()
+                .get(fs, root, root)
+                .unwrap()
+                .resolve_collision(filename.to_string(), &NamingRules::default())
+        };
+
+        assert_eq!(f("a"), "a_000");
+        assert_eq!(f("b_000"), "b_001");
+        assert_eq!(f("c.txt"), "c_000.txt");
+        assert_eq!(f("d_333.txt"), "d_334.txt");
+        assert_eq!(f("e_999.txt"), "e_1000.txt");
+        assert_eq!(f("e_1000.txt"), "e_1000_000.txt");
+        assert_eq!(f("z___222.txt"), "z___223.txt");
+        assert_eq!(f(".x._._._222.txt"), ".x._._._223.txt");
+    }
+
+    #[test]
+    fn test_transactional_roll_back() {
+        let fs = rsfs::mem::FS::new();
+        let root = Path::new("/");
+        fs.create_dir(root.join("a b")).unwrap();
+        fs.create_file(root.join("a b/c d.txt")).unwrap();
+
+        let flags = Flags::parse_from(["unf", "-f", "--transactional"]);
+        let mut unixizer = Unixizer::new(&fs, root, &flags, None);
+        let rename = |from: &str, to: &str| Rename {
+            from: from.into(),
+            to: to.into(),
+        };
+        let plan = vec![
+            rename("a b/c d.txt", "a b/c_d.txt"),
+            rename("a b", "a_b"),
+            rename("missing", "also_missing"),
+        ];
+        let err = unixizer.apply_plan(plan).unwrap_err();
+        assert!(err.to_string().ends_with("; rolled back 2 renames"));
+        assert!(unixizer.renames.is_empty());
+        assert!(fs.metadata(root.join("a b/c d.txt")).is_ok());
+        assert!(fs.metadata(root.join("a_b")).is_err());
+    }
+
+    #[test]
+    fn test_rename_taken_meanwhile() {
+        let fs = rsfs::mem::FS::new();
+        let root = Path::new("/");
+        fs.create_file(root.join("a b")).unwrap();
+        fs.create_file(root.join("a_b")).unwrap();
+
+        // The index does not know about `a_b`, as if it was created after the
+        // directory was read
+        let flags = Flags::parse_from(["unf", "-f"]);
+        let mut unixizer = Unixizer::new(&fs, root, &flags, None);
+        let names = DirNames::new([OsString::from("a b")]);
+        unixizer.dir_index.insert(root, root, names);
+        unixizer.unixize_path(Path::new("a b")).unwrap();
+        assert_eq!(
+            unixizer.renames,
+            [Rename {
+                from: "a b".into(),
+                to: "a_b_000".into(),
+            }]
+        );
+        assert!(fs.metadata(root.join("a_b")).is_ok());
+    }
+
+    #[test]
+    fn test_on_duplicate() {
+        let fs = rsfs::mem::FS::new();
+        let root = Path::new("/");
+        for (name, content) in [("a b", "x"), ("a_b", "x"), ("c d", "x"), ("c_d", "y")] {
+            fs.create_file(root.join(name))
+                .unwrap()
+                .write_all(content.as_bytes())
+                .unwrap();
+        }
+
+        let flags = Flags::parse_from(["unf", "-f", "--on-duplicate", "remove"]);
+        let mut unixizer = Unixizer::new(&fs, root, &flags, None);
+        unixizer
+            .unixize_paths(&["a b".into(), "c d".into()])
+            .unwrap();
+        assert_eq!(
+            unixizer.renames,
+            [Rename {
+                from: "c d".into(),
+                to: "c_d_000".into(),
+            }]
+        );
+        assert!(fs.metadata(root.join("a b")).is_err());
+        assert!(fs.metadata(root.join("a_b")).is_ok());
+    }
+
+    #[test]
+    fn test_merge_dirs() {
+        let fs = rsfs::mem::FS::new();
+        let root = Path::new("/");
+        for dir in ["My Files/sub dir", "My_Files/sub_dir"] {
+            fs.create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "My Files/a b",
+            "My Files/c",
+            "My Files/sub dir/d",
+            "My_Files/a_b",
+        ] {
+            fs.create_file(root.join(file)).unwrap();
+        }
+
+        let flags = Flags::parse_from(["unf", "-f", "-r", "--merge-dirs"]);
+        let mut unixizer = Unixizer::new(&fs, root, &flags, None);
+        unixizer.unixize_path(Path::new("My Files")).unwrap();
+        let names = |dir: &str| read_children_names(&fs, root, Path::new(dir)).unwrap();
+        assert_eq!(names(""), BTreeSet::from(["My_Files".into()]));
+        assert_eq!(
+            names("My_Files"),
+            BTreeSet::from(["a_b".into(), "a_b_000".into(), "c".into(), "sub_dir".into()])
+        );
+        assert_eq!(names("My_Files/sub_dir"), BTreeSet::from(["d".into()]));
+        assert_eq!(unixizer.renames.len(), 5);
+    }
+
+    #[test]
+    fn test_plan_through_swapped_symlink() {
+        let fs = rsfs::mem::FS::new();
+        let root = Path::new("/");
+        for dir in ["a/sub", "elsewhere"] {
+            fs.create_dir_all(root.join(dir)).unwrap();
+        }
+        fs.create_file(root.join("a/sub/x y")).unwrap();
+        fs.create_file(root.join("elsewhere/x y")).unwrap();
+
+        // The directory is swapped for a symlink after planning
+        fs.rename(root.join("a/sub"), root.join("a/moved")).unwrap();
+        fs.symlink("/elsewhere", root.join("a/sub")).unwrap();
+
+        let flags = Flags::parse_from(["unf", "-rf", "--transactional"]);
+        let mut unixizer = Unixizer::new(&fs, root, &flags, None);
+        unixizer.roots = vec!["a".into()];
+        let plan = vec![Rename {
+            from: "a/sub/x y".into(),
+            to: "a/sub/x_y".into(),
+        }];
+        assert!(unixizer.apply_plan(plan).is_err());
+        assert!(fs.metadata(root.join("elsewhere/x y")).is_ok());
     }
 }
@@ -1,10 +1,15 @@
 //! Command-line options
 
+use std::path::Path;
 use std::path::PathBuf;
 
 /// Parsed command-line arguments
 #[derive(clap::Parser, Debug)]
-#[structopt(about)]
+#[structopt(
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Opts {
     /// The paths of filenames to unixize
     #[structopt(required = true)]
@@ -13,10 +18,101 @@ pub struct Opts {
     /// Program flags
     #[structopt(flatten)]
     pub flags: Flags,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Subcommands, used instead of paths
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Watch a directory and unixize entries created in or moved into it,
+    /// once they stop changing
+    #[cfg(all(feature = "watch", target_os = "linux"))]
+    Watch(WatchOpts),
+
+    /// Write a copy of a zip or tar archive with every member path unixized.
+    /// The format is determined from the extension of the input archive.
+    Archive(ArchiveOpts),
+
+    /// Extract a zip or tar archive, unixizing member paths as they are
+    /// extracted
+    Extract(ExtractOpts),
+
+    /// Read a tar stream on standard input and write it to standard output
+    /// with every member path unixized, in a single pass
+    TarFilter,
+
+    /// Perform exactly the renames in a JSON mapping written by
+    /// `--emit-script json`, checking that each can be done before renaming
+    /// anything
+    Apply(ApplyOpts),
+}
+
+/// Arguments of `unf watch`
+#[cfg(all(feature = "watch", target_os = "linux"))]
+#[derive(clap::Parser, Debug)]
+pub struct WatchOpts {
+    /// The directory to watch
+    pub dir: PathBuf,
+
+    /// Wait until an entry has not changed for this many milliseconds before
+    /// renaming it
+    #[structopt(long, value_name = "MS", default_value = "1000")]
+    pub debounce: u64,
+
+    /// Program flags. Prompting is never done, as if using `--force`.
+    #[structopt(flatten)]
+    pub flags: Flags,
+}
+
+/// Arguments of `unf archive`
+#[derive(clap::Parser, Debug)]
+pub struct ArchiveOpts {
+    /// The archive to read (.zip, .tar, .tar.gz, .tar.xz, or .tar.zst)
+    pub input: PathBuf,
+
+    /// Where to write the archive with unixized paths, in the same format.
+    /// Must not exist already.
+    pub output: PathBuf,
+}
+
+/// Arguments of `unf extract`
+#[derive(clap::Parser, Debug)]
+pub struct ExtractOpts {
+    /// The archive to extract (.zip, .tar, .tar.gz, .tar.xz, or .tar.zst)
+    pub archive: PathBuf,
+
+    /// Extract into this directory instead of the current one
+    #[structopt(
+        short = 'C',
+        long = "directory",
+        value_name = "DIR",
+        default_value = "."
+    )]
+    pub dir: PathBuf,
+}
+
+/// Arguments of `unf apply`
+#[derive(clap::Parser, Debug)]
+pub struct ApplyOpts {
+    /// The mapping file to apply
+    pub mapping: PathBuf,
+
+    /// Resolve the paths in the mapping from this directory instead of the
+    /// current one. The mapping must then only have relative paths without
+    /// `..` components.
+    #[structopt(long, value_name = "DIR")]
+    pub root: Option<PathBuf>,
+
+    /// Do not actually rename files. Only check the mapping and print the
+    /// renames that would happen.
+    #[structopt(long, short)]
+    pub dry_run: bool,
 }
 
 /// Parsed command-line flags
-#[derive(clap::Parser, Debug, Copy, Clone)]
+#[derive(clap::Parser, Debug, Clone)]
 #[structopt(about)]
 pub struct Flags {
     /// Recursively unixize filenames in directories. If some of the specified
@@ -31,4 +127,202 @@ pub struct Flags {
     /// Do not actually rename files. Only print the renames that would happen.
     #[structopt(long, short, conflicts_with = "force")]
     pub dry_run: bool,
+
+    /// Read directories in parallel with this many threads (0 for one per CPU)
+    /// before renaming anything. Requires `--force`, `--dry-run`, or `--tui`.
+    #[structopt(long, short, value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// Do not rename anything. Instead, print a script in this format that
+    /// performs the renames, to review and run later or on another host.
+    #[structopt(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        conflicts_with_all = &["force", "update-symlinks", "rewrite-refs"]
+    )]
+    pub emit_script: Option<ScriptFormat>,
+
+    /// With `--emit-script`, also write a script that undoes the renames to
+    /// this file, which must not exist yet
+    #[structopt(long, value_name = "PATH", requires = "emit-script")]
+    pub undo_script: Option<PathBuf>,
+
+    /// Plan all renames before performing them, and if one of them fails or
+    /// unf is interrupted, roll back the renames already performed. Requires
+    /// `--force`, `--dry-run`, or `--tui`.
+    #[structopt(long)]
+    pub transactional: bool,
+
+    /// When the new name of a file, or that name without its
+    /// collision-resolving number or duplicate marker, is taken by a file
+    /// with the same content, remove the file or move it to the trash instead
+    /// of renaming it.
+    #[structopt(
+        long,
+        value_enum,
+        value_name = "ACTION",
+        conflicts_with_all = &["jobs", "tui", "transactional", "emit-script"]
+    )]
+    pub on_duplicate: Option<DuplicateAction>,
+
+    /// When the new name of a directory is taken by another directory, move
+    /// its entries into that directory and remove it, instead of renaming it
+    /// with a collision-resolving number. Entries are merged recursively.
+    #[structopt(
+        long,
+        conflicts_with_all = &["jobs", "tui", "transactional", "emit-script"]
+    )]
+    pub merge_dirs: bool,
+
+    /// Review all planned renames in a full-screen interface before applying
+    /// them, instead of prompting for each.
+    #[structopt(long, conflicts_with = "force")]
+    pub tui: bool,
+
+    /// Only rename entries of this type. Directories are still descended into
+    /// when using `--recursive`, even if they are not renamed.
+    #[structopt(long = "type", short = 't', value_enum, value_name = "TYPE")]
+    pub entry_type: Option<EntryType>,
+
+    /// Descend into symlinks to directories when using `--recursive`. By
+    /// default, symlinks are renamed themselves but never followed.
+    #[structopt(long, short = 'L')]
+    pub follow_symlinks: bool,
+
+    /// After renaming, rewrite symlinks in the processed paths that point at
+    /// renamed entries, keeping relative targets relative.
+    #[structopt(long)]
+    pub update_symlinks: bool,
+
+    /// Rename files tracked by git with `git mv`, so that the renames are
+    /// staged and history is kept. Untracked files are renamed normally.
+    #[structopt(long)]
+    pub git: bool,
+
+    /// After renaming, replace references to renamed entries in the text files
+    /// matching this glob (for example `*.md`), such as links in Markdown or
+    /// HTML and entries in playlists. Can be given multiple times.
+    #[structopt(long, value_name = "GLOB")]
+    pub rewrite_refs: Vec<glob::Pattern>,
+
+    /// What to do with special files (FIFOs, sockets, and device nodes).
+    #[structopt(long, value_enum, value_name = "POLICY", default_value = "warn")]
+    pub special: SpecialPolicy,
+
+    /// Treat this multi-part extension (for example `pkg.tar`) as a single
+    /// extension, so that collision-resolving numbers go before all of it.
+    /// Extensions like `tar.gz`, `user.js`, and `d.ts`, and subtitle
+    /// languages like `en.srt`, are recognized by default. Can be given
+    /// multiple times.
+    #[structopt(long, value_name = "EXT")]
+    pub compound_ext: Vec<String>,
+
+    /// Lowercase extensions, for example `IMG.JPG` to `IMG.jpg`.
+    #[structopt(long)]
+    pub lowercase_ext: bool,
+
+    /// Lowercase extensions and replace common synonyms: `jpeg` and `jpe` with
+    /// `jpg`, `tiff` with `tif`, `htm` with `html`, and `mpeg` with `mpg`.
+    #[structopt(long)]
+    pub normalize_ext: bool,
+
+    /// Replace the extension `FROM`, matched case-insensitively, with `TO`,
+    /// for example `--ext-map jpeg=jpg`. Takes precedence over
+    /// `--normalize-ext`. Can be given multiple times.
+    #[structopt(long, value_name = "FROM=TO", value_parser = parse_ext_mapping)]
+    pub ext_map: Vec<(String, String)>,
+
+    /// Replace dots inside the stem with underscores, for example
+    /// `Show.S01E02.720p.mkv` to `Show_S01E02_720p.mkv`. The extension,
+    /// including compound extensions like `tar.gz`, and a leading dot are
+    /// kept. Directories have no extension, so all of their other dots are
+    /// replaced.
+    #[structopt(long)]
+    pub replace_stem_dots: bool,
+
+    /// Turn duplicate markers added by browsers and file managers, like
+    /// `name (1).ext`, `name - Copy.ext`, or `name copy 2.ext`, into
+    /// collision-resolving numbers, for example `name_000.ext`. A bare `-1`,
+    /// like in `name-1.ext`, is only a marker next to `name.ext`.
+    #[structopt(long)]
+    pub normalize_markers: bool,
+}
+
+/// Parse an `--ext-map` argument of the form `FROM=TO`
+fn parse_ext_mapping(arg: &str) -> Result<(String, String), String> {
+    let (from, to) = match arg.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => (from, to),
+        _ => return Err(format!("expected FROM=TO, got '{}'", arg)),
+    };
+    if !to
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err(format!("'{}' is not a unix-friendly extension", to));
+    }
+    Ok((from.to_string(), to.to_string()))
+}
+
+impl Flags {
+    /// Returns `true` if the name of the file at `path` matches one of the
+    /// `--rewrite-refs` patterns
+    pub fn rewrites_refs_in(&self, path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                self.rewrite_refs
+                    .iter()
+                    .any(|pattern| pattern.matches(name))
+            })
+    }
+}
+
+/// Kind of filesystem entry that can be selected with `--type`
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EntryType {
+    /// Regular files
+    #[clap(name = "f", alias = "file")]
+    File,
+
+    /// Directories
+    #[clap(name = "d", alias = "dir")]
+    Dir,
+
+    /// Symbolic links
+    #[clap(name = "l", alias = "symlink")]
+    Symlink,
+}
+
+/// Policy for renaming special files
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpecialPolicy {
+    /// Skip special files, printing a warning
+    Warn,
+
+    /// Skip special files silently
+    Skip,
+
+    /// Rename special files like any other file
+    Rename,
+}
+
+/// Format of the script printed by `--emit-script`
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScriptFormat {
+    /// POSIX shell script of `mv` commands
+    Sh,
+
+    /// JSON mapping of old to new paths, which `unf apply` can replay
+    Json,
+}
+
+/// What to do with a file whose new name is taken by an identical file
+#[derive(clap::ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DuplicateAction {
+    /// Remove the duplicate
+    Remove,
+
+    /// Move the duplicate to the trash
+    Trash,
 }
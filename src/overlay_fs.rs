@@ -0,0 +1,488 @@
+//! Overlay over the real filesystem for `--dry-run`, which reads through to
+//! the disk and records renames and removals in memory instead of performing
+//! them
+
+use crate::fs_ext;
+use crate::fs_ext::FSExt;
+use crate::fs_ext::FileId;
+use crate::EntryKind;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use rsfs::unix_ext::GenFSExt;
+use rsfs::GenFS;
+use rsfs::Metadata as _;
+
+/// Error for operations that would modify the disk
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "operation not supported in a dry run",
+    )
+}
+
+/// Changes recorded for the contents of a single real directory
+#[derive(Debug, Default)]
+struct DirDelta {
+    /// Entries moved into the directory, by name, with their real paths
+    added: HashMap<OsString, PathBuf>,
+
+    /// Names of real entries moved out of or removed from the directory
+    removed: HashSet<OsString>,
+}
+
+/// Filesystem that reads through to the disk, with renames and removals of
+/// files and empty directories applied in memory. Paths must be absolute.
+/// Directories are only read when asked for, so nothing is loaded up front.
+/// Other operations that would create, modify, or remove entries fail with
+/// `ErrorKind::Unsupported`.
+///
+/// Symlinks are resolved on the real disk, so a symlink keeps pointing at the
+/// real location of its target even if the target was renamed in the overlay.
+#[derive(Debug, Default)]
+pub struct FS {
+    /// Changes to the contents of directories, keyed by their canonical real
+    /// paths so that they survive renames of the directories themselves
+    deltas: RwLock<HashMap<PathBuf, DirDelta>>,
+
+    /// Canonical real paths of the directories resolved so far. The disk is
+    /// never modified, so these never go stale.
+    canonical: RwLock<HashMap<PathBuf, PathBuf>>,
+}
+
+impl FS {
+    /// Canonicalize the real path `path`, following all symlinks
+    fn canonicalize_real(&self, path: &Path) -> io::Result<PathBuf> {
+        if let Some(canonical) = self.canonical.read().unwrap().get(path) {
+            return Ok(canonical.clone());
+        }
+        let canonical = std::fs::canonicalize(path)?;
+        self.canonical
+            .write()
+            .unwrap()
+            .insert(path.to_path_buf(), canonical.clone());
+        Ok(canonical)
+    }
+
+    /// Find the real path of the entry named `name` in the overlay directory
+    /// whose canonical real path is `dir`
+    fn real_child(&self, dir: &Path, name: &OsString) -> io::Result<PathBuf> {
+        if let Some(delta) = self.deltas.read().unwrap().get(dir) {
+            if let Some(real) = delta.added.get(name) {
+                return Ok(real.clone());
+            }
+            if delta.removed.contains(name) {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+        }
+        Ok(dir.join(name))
+    }
+
+    /// Resolve the parent of the overlay path `path` to a canonical real
+    /// directory, returning it along with the final component of `path`, or
+    /// `None` if `path` is the root
+    fn real_parent(&self, path: &Path) -> io::Result<(PathBuf, Option<OsString>)> {
+        if !path.is_absolute() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "overlay paths must be absolute",
+            ));
+        }
+
+        let mut dir = PathBuf::from("/");
+        let mut name: Option<OsString> = None;
+        for component in path.components() {
+            if let Some(name) = name.take() {
+                dir = self.canonicalize_real(&self.real_child(&dir, &name)?)?;
+            }
+            match component {
+                Component::Normal(component) => name = Some(component.to_os_string()),
+                Component::ParentDir => {
+                    dir.pop();
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+            }
+        }
+        Ok((dir, name))
+    }
+
+    /// Record that the entry named `name` was removed from the overlay
+    /// directory whose canonical real path is `dir`
+    fn remove_child(&self, dir: PathBuf, name: OsString) {
+        let mut deltas = self.deltas.write().unwrap();
+        let delta = deltas.entry(dir).or_default();
+        if delta.added.remove(&name).is_none() {
+            delta.removed.insert(name);
+        }
+    }
+
+    /// Find the real path of the entry at the overlay path `path`. The final
+    /// component is not resolved, so the result may be a symlink.
+    fn real_path(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.real_parent(path)? {
+            (dir, Some(name)) => self.real_child(&dir, &name),
+            (dir, None) => Ok(dir),
+        }
+    }
+}
+
+/// Entry returned by `FS::read_dir()`
+#[derive(Debug)]
+pub struct DirEntry {
+    /// Path of the entry in the overlay
+    path: PathBuf,
+
+    /// Real path of the entry on disk
+    real: PathBuf,
+}
+
+impl rsfs::DirEntry for DirEntry {
+    type Metadata = rsfs::disk::Metadata;
+    type FileType = rsfs::disk::FileType;
+
+    fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    fn metadata(&self) -> io::Result<Self::Metadata> {
+        rsfs::disk::FS.symlink_metadata(&self.real)
+    }
+
+    fn file_type(&self) -> io::Result<Self::FileType> {
+        Ok(self.metadata()?.file_type())
+    }
+
+    fn file_name(&self) -> OsString {
+        self.path.file_name().unwrap_or_default().to_os_string()
+    }
+}
+
+/// Directory builder that refuses to create directories
+#[derive(Debug)]
+pub struct DirBuilder;
+
+impl rsfs::DirBuilder for DirBuilder {
+    fn recursive(&mut self, _recursive: bool) -> &mut Self {
+        self
+    }
+
+    fn create<P: AsRef<Path>>(&self, _path: P) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+/// Open options that refuse to open files, since they could be used to write
+/// to the disk. Use `FS::open_file()` to read files.
+#[derive(Clone, Debug)]
+pub struct OpenOptions;
+
+impl rsfs::OpenOptions for OpenOptions {
+    type File = rsfs::disk::File;
+
+    fn read(&mut self, _read: bool) -> &mut Self {
+        self
+    }
+
+    fn write(&mut self, _write: bool) -> &mut Self {
+        self
+    }
+
+    fn append(&mut self, _append: bool) -> &mut Self {
+        self
+    }
+
+    fn truncate(&mut self, _truncate: bool) -> &mut Self {
+        self
+    }
+
+    fn create(&mut self, _create: bool) -> &mut Self {
+        self
+    }
+
+    fn create_new(&mut self, _create_new: bool) -> &mut Self {
+        self
+    }
+
+    fn open<P: AsRef<Path>>(&self, _path: P) -> io::Result<Self::File> {
+        Err(unsupported())
+    }
+}
+
+impl GenFS for FS {
+    type DirBuilder = DirBuilder;
+    type DirEntry = DirEntry;
+    type File = rsfs::disk::File;
+    type Metadata = rsfs::disk::Metadata;
+    type OpenOptions = OpenOptions;
+    type Permissions = rsfs::disk::Permissions;
+    type ReadDir = std::vec::IntoIter<io::Result<DirEntry>>;
+
+    fn canonicalize<P: AsRef<Path>>(&self, _path: P) -> io::Result<PathBuf> {
+        Err(unsupported())
+    }
+
+    fn copy<P: AsRef<Path>, Q: AsRef<Path>>(&self, _from: P, _to: Q) -> io::Result<u64> {
+        Err(unsupported())
+    }
+
+    fn create_dir<P: AsRef<Path>>(&self, _path: P) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn create_dir_all<P: AsRef<Path>>(&self, _path: P) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(&self, _src: P, _dst: Q) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn metadata<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::Metadata> {
+        rsfs::disk::FS.metadata(self.real_path(path.as_ref())?)
+    }
+
+    /// Read the real directory, with the entries moved into and out of it in
+    /// the overlay added and removed
+    fn read_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::ReadDir> {
+        let path = path.as_ref();
+        let dir = self.canonicalize_real(&self.real_path(path)?)?;
+
+        let deltas = self.deltas.read().unwrap();
+        let delta = deltas.get(&dir);
+        let mut entries = Vec::new();
+        for ent in std::fs::read_dir(&dir)? {
+            let name = ent?.file_name();
+            if delta.is_some_and(|delta| {
+                delta.removed.contains(&name) || delta.added.contains_key(&name)
+            }) {
+                continue;
+            }
+            entries.push(Ok(DirEntry {
+                path: path.join(&name),
+                real: dir.join(&name),
+            }));
+        }
+        if let Some(delta) = delta {
+            for (name, real) in &delta.added {
+                entries.push(Ok(DirEntry {
+                    path: path.join(name),
+                    real: real.clone(),
+                }));
+            }
+        }
+        Ok(entries.into_iter())
+    }
+
+    fn read_link<P: AsRef<Path>>(&self, path: P) -> io::Result<PathBuf> {
+        rsfs::disk::FS.read_link(self.real_path(path.as_ref())?)
+    }
+
+    /// Record that the empty directory at `path` was removed, without
+    /// touching the disk
+    fn remove_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let (dir, name) = self.real_parent(path.as_ref())?;
+        let name = name.ok_or(io::ErrorKind::InvalidInput)?;
+        if !std::fs::symlink_metadata(self.real_child(&dir, &name)?)?.is_dir() {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+        if self.read_dir(path)?.next().is_some() {
+            return Err(io::ErrorKind::DirectoryNotEmpty.into());
+        }
+
+        self.remove_child(dir, name);
+        Ok(())
+    }
+
+    fn remove_dir_all<P: AsRef<Path>>(&self, _path: P) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    /// Record that the file at `path` was removed, without touching the disk
+    fn remove_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let (dir, name) = self.real_parent(path.as_ref())?;
+        let name = name.ok_or(io::ErrorKind::IsADirectory)?;
+        if std::fs::symlink_metadata(self.real_child(&dir, &name)?)?.is_dir() {
+            return Err(io::ErrorKind::IsADirectory.into());
+        }
+
+        self.remove_child(dir, name);
+        Ok(())
+    }
+
+    /// Record that the entry at `from` is now at `to`, without touching the
+    /// disk
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "cannot rename the root");
+        let (from_dir, from_name) = self.real_parent(from.as_ref())?;
+        let from_name = from_name.ok_or_else(invalid)?;
+        let (to_dir, to_name) = self.real_parent(to.as_ref())?;
+        let to_name = to_name.ok_or_else(invalid)?;
+
+        let real = self.real_child(&from_dir, &from_name)?;
+        std::fs::symlink_metadata(&real)?;
+
+        self.remove_child(from_dir, from_name);
+        self.deltas
+            .write()
+            .unwrap()
+            .entry(to_dir)
+            .or_default()
+            .added
+            .insert(to_name, real);
+        Ok(())
+    }
+
+    fn set_permissions<P: AsRef<Path>>(
+        &self,
+        _path: P,
+        _perm: Self::Permissions,
+    ) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn symlink_metadata<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::Metadata> {
+        rsfs::disk::FS.symlink_metadata(self.real_path(path.as_ref())?)
+    }
+
+    fn new_openopts(&self) -> Self::OpenOptions {
+        OpenOptions
+    }
+
+    fn new_dirbuilder(&self) -> Self::DirBuilder {
+        DirBuilder
+    }
+
+    fn open_file<P: AsRef<Path>>(&self, path: P) -> io::Result<Self::File> {
+        rsfs::disk::FS.open_file(self.real_path(path.as_ref())?)
+    }
+
+    fn create_file<P: AsRef<Path>>(&self, _path: P) -> io::Result<Self::File> {
+        Err(unsupported())
+    }
+}
+
+impl GenFSExt for FS {
+    fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(&self, _src: P, _dst: Q) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+impl FSExt for FS {
+    fn file_id<P: AsRef<Path>>(&self, path: P) -> io::Result<FileId> {
+        let stat = std::fs::metadata(self.real_path(path.as_ref())?)?;
+        Ok((stat.dev(), stat.ino()))
+    }
+
+    /// Files are removed from the overlay instead
+    fn trash<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.remove_file(path)
+    }
+
+    type Dir = PathBuf;
+
+    fn open_dir<P: AsRef<Path>>(&self, path: P) -> io::Result<PathBuf> {
+        fs_ext::path_open_dir(self, path.as_ref())
+    }
+
+    fn open_dir_at(
+        &self,
+        dir: &PathBuf,
+        name: &OsStr,
+        follow_symlinks: bool,
+    ) -> io::Result<PathBuf> {
+        fs_ext::path_open_dir_at(self, dir, name, follow_symlinks)
+    }
+
+    fn rename_at(
+        &self,
+        from_dir: &PathBuf,
+        from: &OsStr,
+        to_dir: &PathBuf,
+        to: &OsStr,
+    ) -> io::Result<()> {
+        fs_ext::path_rename_at(self, from_dir, from, to_dir, to)
+    }
+
+    fn remove_file_at(&self, dir: &PathBuf, name: &OsStr) -> io::Result<()> {
+        self.remove_file(dir.join(name))
+    }
+
+    fn read_dir_at(&self, dir: &PathBuf) -> io::Result<Vec<OsString>> {
+        fs_ext::path_read_dir_at(self, dir)
+    }
+
+    fn kind_at(&self, dir: &PathBuf, name: &OsStr, follow_symlinks: bool) -> io::Result<EntryKind> {
+        fs_ext::path_kind_at(self, dir, name, follow_symlinks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+
+    use rsfs::DirEntry as _;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_rename() {
+        let tmp = TempDir::new().unwrap();
+        let tmp = tmp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(tmp.join("a b/c d")).unwrap();
+        std::fs::File::create(tmp.join("a b/c d/e f")).unwrap();
+        std::fs::File::create(tmp.join("a b/g")).unwrap();
+
+        let fs = FS::default();
+        let names = |path: &str| -> BTreeSet<String> {
+            fs.read_dir(tmp.join(path))
+                .unwrap()
+                .map(|ent| ent.unwrap().file_name().into_string().unwrap())
+                .collect()
+        };
+        let exists = |path: &str| fs.symlink_metadata(tmp.join(path)).is_ok();
+
+        fs.rename(tmp.join("a b/c d/e f"), tmp.join("a b/c d/e_f"))
+            .unwrap();
+        fs.rename(tmp.join("a b/c d"), tmp.join("a b/c_d")).unwrap();
+        fs.rename(tmp.join("a b"), tmp.join("a_b")).unwrap();
+
+        // Renames are visible in the overlay, including below renamed
+        // directories
+        assert_eq!(names(""), BTreeSet::from(["a_b".into()]));
+        assert_eq!(names("a_b"), BTreeSet::from(["c_d".into(), "g".into()]));
+        assert_eq!(names("a_b/c_d"), BTreeSet::from(["e_f".into()]));
+        assert!(exists("a_b/c_d/e_f"));
+        assert!(!exists("a b"));
+        assert!(!exists("a_b/c d"));
+
+        // Moving an entry back makes it visible at its original path again
+        fs.rename(tmp.join("a_b/g"), tmp.join("a_b/h")).unwrap();
+        fs.rename(tmp.join("a_b/h"), tmp.join("a_b/g")).unwrap();
+        assert!(exists("a_b/g"));
+        assert!(!exists("a_b/h"));
+
+        // Renaming missing entries fails
+        assert!(fs.rename(tmp.join("a b"), tmp.join("x")).is_err());
+
+        // Only empty directories can be removed
+        assert!(fs.remove_dir(tmp.join("a_b/c_d")).is_err());
+        fs.remove_file(tmp.join("a_b/c_d/e_f")).unwrap();
+        fs.remove_dir(tmp.join("a_b/c_d")).unwrap();
+        assert_eq!(names("a_b"), BTreeSet::from(["g".into()]));
+
+        // The disk is untouched
+        assert!(tmp.join("a b/c d/e f").exists());
+        assert!(!tmp.join("a_b").exists());
+        assert!(fs.create_file(tmp.join("new")).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! Plan renames in parallel without performing them
+
+use crate::dir_index::DirNames;
+use crate::filename_parts::NamingRules;
+use crate::fs_ext::FSExt;
+use crate::fs_ext::FileId;
+use crate::opts::Flags;
+use crate::pick_new_name;
+use crate::renames::normalize;
+use crate::renames::Rename;
+use crate::signals;
+use crate::Descent;
+use crate::EntryKind;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use rayon::prelude::*;
+use rsfs::DirEntry;
+
+/// Read the names of the entries in `dir`, in sorted order
+fn read_names<FS: FSExt>(fs: &FS, cwd: &Path, dir: &Path) -> io::Result<Vec<OsString>> {
+    let mut names = fs
+        .read_dir(cwd.join(dir))?
+        .map(|ent| ent.map(|ent| ent.file_name()))
+        .collect::<io::Result<Vec<OsString>>>()?;
+    names.sort();
+    Ok(names)
+}
+
+/// Plans renames for the same paths that `unixize_paths()` would rename, in
+/// the same order and with the same collision resolution. Directories are
+/// read in parallel, so this is only usable when not prompting.
+pub struct Planner<'a, FS: FSExt> {
+    fs: &'a FS,
+    cwd: &'a Path,
+    flags: &'a Flags,
+
+    /// How filenames are split and rewritten, built from `flags`
+    rules: NamingRules,
+}
+
+impl<'a, FS: FSExt> Planner<'a, FS> {
+    pub fn new(fs: &'a FS, cwd: &'a Path, flags: &'a Flags) -> Self {
+        Self {
+            fs,
+            cwd,
+            flags,
+            rules: NamingRules::from_flags(flags),
+        }
+    }
+
+    /// If the entry of kind `kind` at `path` should be renamed, pick its new
+    /// name, avoiding the names `taken` in its directory and updating them
+    fn plan_rename(&self, path: &Path, kind: EntryKind, taken: &mut DirNames) -> Option<Rename> {
+        let (_, new_basename) = pick_new_name(path, kind, self.flags, &self.rules, taken)?;
+        taken.rename(path.file_name()?, OsStr::new(&new_basename), &self.rules);
+        Some(Rename {
+            from: path.to_path_buf(),
+            to: path.with_file_name(new_basename),
+        })
+    }
+
+    /// Plan the renames of the children of `dir` and everything below them.
+    /// Children are inspected in parallel, but their renames are planned in
+    /// sorted order so that collisions are resolved deterministically.
+    fn plan_children(&self, dir: &Path, ancestors: &[FileId]) -> io::Result<Vec<Rename>> {
+        // Stop reading directories after SIGINT or SIGTERM; nothing is
+        // renamed then anyway
+        if signals::caught().is_some() {
+            return Ok(Vec::new());
+        }
+
+        let mut ancestors = ancestors.to_vec();
+        match Descent::check(
+            self.fs,
+            self.cwd,
+            dir,
+            self.flags.follow_symlinks,
+            &ancestors,
+        )? {
+            Descent::Into(id) => ancestors.extend(id),
+            Descent::Loop => return Ok(Vec::new()),
+        }
+
+        let names = read_names(self.fs, self.cwd, dir)?;
+        let children = names
+            .par_iter()
+            .map(|name| {
+                let path = dir.join(name);
+                let (kind, is_dir) =
+                    EntryKind::inspect(self.fs, self.cwd, &path, self.flags.follow_symlinks)?;
+                let plan = if self.flags.recursive && is_dir {
+                    self.plan_children(&path, &ancestors)?
+                } else {
+                    Vec::new()
+                };
+                Ok((kind, plan))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Renames of children come after the renames below them, like in
+        // `unixize_path()`
+        let mut taken = DirNames::new(names.iter().cloned());
+        let mut plan = Vec::new();
+        for (name, (kind, children_plan)) in names.iter().zip(children) {
+            plan.extend(children_plan);
+            plan.extend(self.plan_rename(&dir.join(name), kind, &mut taken));
+        }
+        Ok(plan)
+    }
+
+    /// Plan the renames for unixizing `paths`, like `unixize_paths()` would
+    /// perform them
+    pub fn plan_paths(&self, paths: &[PathBuf]) -> io::Result<Vec<Rename>> {
+        // Names taken in the parent directories of `paths`, which are only read
+        // when needed
+        let mut taken_by_dir: HashMap<PathBuf, DirNames> = HashMap::new();
+        let mut plan = Vec::new();
+
+        for path in paths {
+            if path.file_name().is_none() {
+                plan.extend(self.plan_children(path, &[])?);
+                continue;
+            }
+
+            let (kind, is_dir) =
+                EntryKind::inspect(self.fs, self.cwd, path, self.flags.follow_symlinks)?;
+            if self.flags.recursive && is_dir {
+                plan.extend(self.plan_children(path, &[])?);
+            }
+
+            let parent = path.parent().unwrap_or_else(|| Path::new(""));
+            let taken = match taken_by_dir.entry(normalize(self.cwd, parent)) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let names = read_names(self.fs, self.cwd, parent)?;
+                    entry.insert(DirNames::new(names))
+                }
+            };
+            plan.extend(self.plan_rename(path, kind, taken));
+        }
+        Ok(plan)
+    }
+}
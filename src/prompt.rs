@@ -0,0 +1,162 @@
+//! Interactive prompts for confirming renames
+
+use crate::signals;
+use crate::Result;
+
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use signal_hook::consts::SIGINT;
+
+const HELP: &str = "\
+y - yes
+n - no (default)
+a - yes to this and all remaining prompts
+d - no to this and all remaining prompts
+s - no, and skip the rest of this directory
+q - quit, leaving everything else as it is
+e - edit the new name
+? - print this help";
+
+/// Answer to a prompt
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Answer {
+    Yes,
+    No,
+    /// Yes to this and all remaining prompts
+    All,
+    /// No to this and all remaining prompts
+    None,
+    /// No, and skip the remaining entries of the current directory
+    SkipDir,
+    /// Stop without doing anything else
+    Quit,
+    /// Edit the new name before answering again
+    Edit,
+}
+
+impl Answer {
+    /// Parse an answer typed by the user. Empty input means no.
+    fn parse(input: &str, editable: bool) -> Option<Self> {
+        match input.trim().to_lowercase().as_str() {
+            "y" | "yes" => Some(Self::Yes),
+            "" | "n" | "no" => Some(Self::No),
+            "a" | "all" => Some(Self::All),
+            "d" | "none" => Some(Self::None),
+            "s" | "skip" => Some(Self::SkipDir),
+            "q" | "quit" => Some(Self::Quit),
+            "e" | "edit" if editable => Some(Self::Edit),
+            _ => None,
+        }
+    }
+}
+
+/// Reads answers from the terminal, or from standard input if it is not a
+/// terminal
+pub struct Prompter {
+    editor: Editor<()>,
+}
+
+impl Prompter {
+    pub fn new() -> Self {
+        Self {
+            editor: Editor::new(),
+        }
+    }
+
+    /// Read a line, returning `None` at end of input or on interrupt. Ctrl-C
+    /// is read as a key in raw mode instead of raising SIGINT, so it is
+    /// recorded as if SIGINT was caught.
+    fn readline(&mut self, prompt: &str, initial: &str) -> Result<Option<String>> {
+        match self.editor.readline_with_initial(prompt, (initial, "")) {
+            Ok(line) => Ok(Some(line)),
+            Err(ReadlineError::Eof) => Ok(None),
+            Err(ReadlineError::Interrupted) => {
+                signals::record(SIGINT);
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Ask `question` until a valid answer is given. Editing is only offered
+    /// if `editable` is set. The end of input is taken as quitting.
+    pub fn ask(&mut self, question: &str, editable: bool) -> Result<Answer> {
+        let choices = if editable {
+            "y,n,a,d,s,q,e,?"
+        } else {
+            "y,n,a,d,s,q,?"
+        };
+        let prompt = format!("{} [{}] ", question, choices);
+        loop {
+            let input = match self.readline(&prompt, "")? {
+                Some(input) => input,
+                None => return Ok(Answer::Quit),
+            };
+            match Answer::parse(&input, editable) {
+                Some(answer) => return Ok(answer),
+                None if input.trim() == "?" => eprintln!("{}", HELP),
+                None => eprintln!("unf: unknown answer '{}', type '?' for help", input.trim()),
+            }
+        }
+    }
+
+    /// Let the user edit `name`, returning the edited name, or `None` if input
+    /// ended
+    pub fn edit(&mut self, name: &str) -> Result<Option<String>> {
+        self.readline("new name: ", name)
+    }
+}
+
+/// How to confirm descending into directories and renaming entries
+pub enum Confirm {
+    /// Ask the user each time
+    Prompt(Prompter),
+    /// Go ahead without asking
+    Always,
+    /// Decline without asking
+    Never,
+}
+
+impl Confirm {
+    /// Confirm the action described by `question`. Answers that apply to all
+    /// remaining prompts switch to `Always` or `Never`, and are returned as
+    /// `Yes` or `No`.
+    pub fn ask(&mut self, question: &str, editable: bool) -> Result<Answer> {
+        let prompter = match self {
+            Self::Prompt(prompter) => prompter,
+            Self::Always => return Ok(Answer::Yes),
+            Self::Never => return Ok(Answer::No),
+        };
+        match prompter.ask(question, editable)? {
+            Answer::All => {
+                *self = Self::Always;
+                Ok(Answer::Yes)
+            }
+            Answer::None => {
+                *self = Self::Never;
+                Ok(Answer::No)
+            }
+            answer => Ok(answer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_answer() {
+        assert_eq!(Answer::parse("y", true), Some(Answer::Yes));
+        assert_eq!(Answer::parse(" YES\n", true), Some(Answer::Yes));
+        assert_eq!(Answer::parse("", true), Some(Answer::No));
+        assert_eq!(Answer::parse("a", true), Some(Answer::All));
+        assert_eq!(Answer::parse("d", true), Some(Answer::None));
+        assert_eq!(Answer::parse("s", true), Some(Answer::SkipDir));
+        assert_eq!(Answer::parse("q", true), Some(Answer::Quit));
+        assert_eq!(Answer::parse("e", true), Some(Answer::Edit));
+        assert_eq!(Answer::parse("e", false), None);
+        assert_eq!(Answer::parse("?", true), None);
+        assert_eq!(Answer::parse("x", true), None);
+    }
+}
@@ -0,0 +1,266 @@
+//! Update references to renamed files inside text files
+
+use crate::fs_ext::walk;
+use crate::fs_ext::FSExt;
+use crate::opts::Flags;
+use crate::renames::normalize;
+use crate::renames::Rename;
+use crate::renames::RenameMap;
+use crate::Result;
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use aho_corasick::AhoCorasick;
+use aho_corasick::AhoCorasickBuilder;
+use aho_corasick::MatchKind;
+use percent_encoding::utf8_percent_encode;
+use percent_encoding::AsciiSet;
+use percent_encoding::CONTROLS;
+use rsfs::Metadata;
+
+/// Characters that are percent-encoded in the path of a URL
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Compute the path of `to` relative to the directory `from`. Both paths must
+/// be absolute and normalized.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let mut from_components = from.components().peekable();
+    let mut to_components = to.components().peekable();
+    while from_components.peek().is_some() && from_components.peek() == to_components.peek() {
+        from_components.next();
+        to_components.next();
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in from_components {
+        relative.push(Component::ParentDir);
+    }
+    relative.extend(to_components);
+    relative
+}
+
+/// Returns `true` if `c` can separate a path from surrounding text, for
+/// example quotes in HTML or parentheses in Markdown
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "\"'()[]{}<>=,;|".contains(c)
+}
+
+/// Returns `true` if a reference starting at byte `start` of `text` is not
+/// part of a longer path. A leading `./` is allowed.
+fn is_ref_start(text: &str, start: usize) -> bool {
+    let before = &text[..start];
+    let before = before.strip_suffix("./").unwrap_or(before);
+    before.chars().next_back().is_none_or(is_delimiter)
+}
+
+/// Returns `true` if a reference ending at byte `end` of `text` is not part of
+/// a longer path. A following URL fragment or query is allowed.
+fn is_ref_end(text: &str, end: usize) -> bool {
+    text[end..]
+        .chars()
+        .next()
+        .is_none_or(|c| is_delimiter(c) || c == '#' || c == '?')
+}
+
+/// Paths to search for in text files in one directory, and their replacements
+struct Replacements {
+    matcher: AhoCorasick,
+    replacements: Vec<String>,
+}
+
+impl Replacements {
+    /// Build the replacements for text files that were in the directory
+    /// `old_dir` and are now in `new_dir`, given the `moved` entries as pairs
+    /// of absolute old and new paths. Each path is searched for both as-is and
+    /// percent-encoded.
+    fn new(old_dir: &Path, new_dir: &Path, moved: &[(PathBuf, PathBuf)]) -> Self {
+        let mut patterns = Vec::new();
+        let mut replacements = Vec::new();
+        for (old, new) in moved {
+            let old_ref = relative_path(old_dir, old);
+            let new_ref = relative_path(new_dir, new);
+            let (old_ref, new_ref) = match (old_ref.to_str(), new_ref.to_str()) {
+                (Some(old_ref), Some(new_ref)) if old_ref != new_ref => (old_ref, new_ref),
+                _ => continue,
+            };
+
+            let old_encoded = utf8_percent_encode(old_ref, PATH_ENCODE_SET).to_string();
+            let new_encoded = utf8_percent_encode(new_ref, PATH_ENCODE_SET).to_string();
+            if old_encoded != old_ref {
+                patterns.push(old_encoded);
+                replacements.push(new_encoded);
+            }
+            patterns.push(old_ref.to_string());
+            replacements.push(new_ref.to_string());
+        }
+
+        let matcher = AhoCorasickBuilder::new()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(patterns);
+        Self {
+            matcher,
+            replacements,
+        }
+    }
+
+    /// Replace all references in `text`, returning `None` if there are none
+    fn apply(&self, text: &str) -> Option<String> {
+        let mut replaced = String::with_capacity(text.len());
+        let mut last = 0;
+        for mat in self.matcher.find_iter(text) {
+            if is_ref_start(text, mat.start()) && is_ref_end(text, mat.end()) {
+                replaced.push_str(&text[last..mat.start()]);
+                replaced.push_str(&self.replacements[mat.pattern()]);
+                last = mat.end();
+            }
+        }
+        if last == 0 {
+            return None;
+        }
+        replaced.push_str(&text[last..]);
+        Some(replaced)
+    }
+}
+
+/// After `renames` were performed, replace references to renamed entries in
+/// the text files below `roots` (the paths passed on the command line) whose
+/// names match the `--rewrite-refs` patterns. References are paths relative to
+/// the text file, either as-is or percent-encoded as in URLs. Files that are
+/// not valid UTF-8 are skipped.
+pub fn rewrite_refs<FS: FSExt>(
+    fs: &FS,
+    cwd: &Path,
+    roots: &[PathBuf],
+    renames: &[Rename],
+    flags: &Flags,
+) -> Result<()> {
+    if renames.is_empty() {
+        return Ok(());
+    }
+
+    // Find all entries whose paths changed, including those below renamed
+    // directories, and all text files to search
+    let map = RenameMap::new(cwd, renames);
+    let mut moved = Vec::new();
+    let mut text_files = Vec::new();
+    for root in roots {
+        let root = map.forward_relative(cwd, root);
+        walk(fs, cwd, &root, flags.recursive, &mut |path, stat| {
+            let new = normalize(cwd, path);
+            let old = map.backward(&new);
+            if old != new {
+                moved.push((old.clone(), new.clone()));
+            }
+
+            if stat.is_file() && flags.rewrites_refs_in(path) {
+                text_files.push((path.to_path_buf(), old, new));
+            }
+            Ok(())
+        })?;
+    }
+
+    let mut replacements_by_dir = HashMap::new();
+    for (path, old, new) in text_files {
+        let (old_dir, new_dir) = match (old.parent(), new.parent()) {
+            (Some(old_dir), Some(new_dir)) => (old_dir.to_path_buf(), new_dir.to_path_buf()),
+            _ => continue,
+        };
+
+        let mut text = String::new();
+        if fs
+            .open_file(cwd.join(&path))?
+            .read_to_string(&mut text)
+            .is_err()
+        {
+            continue;
+        }
+
+        let replacements = replacements_by_dir
+            .entry((old_dir, new_dir))
+            .or_insert_with_key(|(old_dir, new_dir)| Replacements::new(old_dir, new_dir, &moved));
+        let text = match replacements.apply(&text) {
+            Some(text) => text,
+            None => continue,
+        };
+
+        let prefix = if flags.dry_run {
+            "would update references in"
+        } else {
+            "update references in"
+        };
+        println!("{} '{}'", prefix, path.display());
+        if !flags.dry_run {
+            fs.create_file(cwd.join(&path))?
+                .write_all(text.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path() {
+        let f = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(f("/a/b", "/a/b/c"), Path::new("c"));
+        assert_eq!(f("/a/b", "/a/c/d"), Path::new("../c/d"));
+        assert_eq!(f("/a/b", "/x"), Path::new("../../x"));
+        assert_eq!(f("/", "/a"), Path::new("a"));
+    }
+
+    #[test]
+    fn test_replacements() {
+        let moved = [
+            ("/r/My Files".into(), "/r/My_Files".into()),
+            (
+                "/r/My Files/Cool Photo.JPG".into(),
+                "/r/My_Files/Cool_Photo.JPG".into(),
+            ),
+            ("/r/Track 01.wav".into(), "/r/Track_01.wav".into()),
+        ];
+        let replacements = Replacements::new(Path::new("/r"), Path::new("/r"), &moved);
+        let f = |text: &str| replacements.apply(text);
+
+        assert_eq!(
+            f("![photo](My%20Files/Cool%20Photo.JPG)"),
+            Some("![photo](My_Files/Cool_Photo.JPG)".into())
+        );
+        assert_eq!(
+            f("<img src=\"./My Files/Cool Photo.JPG#top\">"),
+            Some("<img src=\"./My_Files/Cool_Photo.JPG#top\">".into())
+        );
+        assert_eq!(
+            f("FILE \"Track 01.wav\" WAVE\n"),
+            Some("FILE \"Track_01.wav\" WAVE\n".into())
+        );
+        assert_eq!(
+            f("Track 01.wav\nMy Files/Cool Photo.JPG\n"),
+            Some("Track_01.wav\nMy_Files/Cool_Photo.JPG\n".into())
+        );
+        assert_eq!(f("see My Files/Other.txt"), None);
+        assert_eq!(f("Track 01.wavx"), None);
+        assert_eq!(f("https://example.com/Track 01.wav"), None);
+    }
+}
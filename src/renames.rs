@@ -0,0 +1,137 @@
+//! Bookkeeping for renames performed during a run
+
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A rename that was performed, with paths as they were passed to `rename()`.
+/// Lists of renames are saved as mappings by `--emit-script json`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// Lexically normalize `path`, joining it onto `cwd` if it is relative and
+/// removing `.` and `..` components. Symlinks are not resolved.
+pub fn normalize(cwd: &Path, path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Renames with absolute, normalized paths, used to map paths from before a
+/// run to after it and back
+#[derive(Debug)]
+pub struct RenameMap {
+    renames: Vec<Rename>,
+}
+
+impl RenameMap {
+    pub fn new(cwd: &Path, renames: &[Rename]) -> Self {
+        let renames = renames
+            .iter()
+            .map(|rename| Rename {
+                from: normalize(cwd, &rename.from),
+                to: normalize(cwd, &rename.to),
+            })
+            .collect();
+        Self { renames }
+    }
+
+    /// Map an absolute, normalized path from before the renames to where it
+    /// is after them
+    pub fn forward(&self, path: &Path) -> PathBuf {
+        self.renames
+            .iter()
+            .fold(path.to_path_buf(), |path, rename| {
+                replace_prefix(path, &rename.from, &rename.to)
+            })
+    }
+
+    /// Map a path relative to `cwd` from before the renames to where it is
+    /// after them, keeping it relative to `cwd` when possible
+    pub fn forward_relative(&self, cwd: &Path, path: &Path) -> PathBuf {
+        let path = self.forward(&normalize(cwd, path));
+        match path.strip_prefix(cwd) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => path,
+        }
+    }
+
+    /// Map an absolute, normalized path from after the renames to where it
+    /// was before them
+    pub fn backward(&self, path: &Path) -> PathBuf {
+        self.renames
+            .iter()
+            .rev()
+            .fold(path.to_path_buf(), |path, rename| {
+                replace_prefix(path, &rename.to, &rename.from)
+            })
+    }
+}
+
+/// If `path` is `prefix` or is below it, replace `prefix` with `replacement`
+fn replace_prefix(path: PathBuf, prefix: &Path, replacement: &Path) -> PathBuf {
+    match path.strip_prefix(prefix) {
+        Ok(rest) if rest.as_os_str().is_empty() => replacement.to_path_buf(),
+        Ok(rest) => replacement.join(rest),
+        Err(_) => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let cwd = Path::new("/tmp");
+        assert_eq!(normalize(cwd, Path::new("a")), Path::new("/tmp/a"));
+        assert_eq!(normalize(cwd, Path::new("./a/")), Path::new("/tmp/a"));
+        assert_eq!(normalize(cwd, Path::new("a/../b")), Path::new("/tmp/b"));
+        assert_eq!(normalize(cwd, Path::new("..")), Path::new("/"));
+        assert_eq!(normalize(cwd, Path::new("/x/./y")), Path::new("/x/y"));
+    }
+
+    #[test]
+    fn test_rename_map() {
+        let renames = [
+            Rename {
+                from: "My Files/a b".into(),
+                to: "My Files/a_b".into(),
+            },
+            Rename {
+                from: "My Files/".into(),
+                to: "My_Files".into(),
+            },
+        ];
+        let map = RenameMap::new(Path::new("/tmp"), &renames);
+
+        let forward = |path: &str| map.forward(Path::new(path));
+        assert_eq!(forward("/tmp/My Files"), Path::new("/tmp/My_Files"));
+        assert_eq!(forward("/tmp/My Files/a b"), Path::new("/tmp/My_Files/a_b"));
+        assert_eq!(
+            forward("/tmp/My Files/a b/c"),
+            Path::new("/tmp/My_Files/a_b/c")
+        );
+        assert_eq!(forward("/tmp/My Files/c"), Path::new("/tmp/My_Files/c"));
+        assert_eq!(forward("/tmp/My Filesx"), Path::new("/tmp/My Filesx"));
+
+        let backward = |path: &str| map.backward(Path::new(path));
+        assert_eq!(backward("/tmp/My_Files"), Path::new("/tmp/My Files"));
+        assert_eq!(
+            backward("/tmp/My_Files/a_b/c"),
+            Path::new("/tmp/My Files/a b/c")
+        );
+        assert_eq!(backward("/tmp/other"), Path::new("/tmp/other"));
+    }
+}
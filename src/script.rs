@@ -0,0 +1,155 @@
+//! Scripts and mappings of planned renames, printed by `--emit-script`
+
+use crate::opts::ScriptFormat;
+use crate::renames::Rename;
+use crate::Result;
+
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Quote `path` as a single word for a POSIX shell. Single quotes keep every
+/// byte literal except `'` itself, which is written as `'\''`.
+fn quote_sh(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in path.as_os_str().as_bytes() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Write a POSIX shell script performing `renames` in order to `out`. The
+/// script stops at the first rename that fails, or whose target exists, so
+/// that like unf it never overwrites anything.
+fn write_sh<W: Write>(out: &mut W, renames: &[Rename]) -> io::Result<()> {
+    writeln!(out, "#!/bin/sh")?;
+    writeln!(out, "set -e")?;
+    for rename in renames {
+        let from = quote_sh(&rename.from);
+        let to = quote_sh(&rename.to);
+        out.write_all(b"if [ -e ")?;
+        out.write_all(&to)?;
+        out.write_all(b" ] || [ -L ")?;
+        out.write_all(&to)?;
+        out.write_all(b" ]; then printf 'target already exists: %s\\n' ")?;
+        out.write_all(&to)?;
+        out.write_all(b" >&2; exit 1; fi\n")?;
+        out.write_all(b"mv -- ")?;
+        out.write_all(&from)?;
+        out.write_all(b" ")?;
+        out.write_all(&to)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Write a script in `format` performing `renames` to `out`
+fn write_script<W: Write>(out: &mut W, format: ScriptFormat, renames: &[Rename]) -> io::Result<()> {
+    match format {
+        ScriptFormat::Sh => write_sh(out, renames),
+        ScriptFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, renames)?;
+            writeln!(out)
+        }
+    }
+}
+
+/// Print a script in `format` that performs `renames`, and write a script
+/// undoing them to `undo_path` if given. Paths in the scripts are relative to
+/// the current directory, like the renames.
+pub fn emit(format: ScriptFormat, renames: &[Rename], undo_path: Option<&Path>) -> Result<()> {
+    // The undo script never replaces an existing file, which might be the
+    // undo script of an earlier run
+    let undo_file = match undo_path {
+        Some(undo_path) => Some(
+            File::options()
+                .write(true)
+                .create_new(true)
+                .open(undo_path)
+                .map_err(|err| format!("cannot create '{}': {}", undo_path.display(), err))?,
+        ),
+        None => None,
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_script(&mut out, format, renames)?;
+    out.flush()?;
+
+    if let Some(undo_file) = undo_file {
+        let undo: Vec<Rename> = renames
+            .iter()
+            .rev()
+            .map(|rename| Rename {
+                from: rename.to.clone(),
+                to: rename.from.clone(),
+            })
+            .collect();
+        let mut out = BufWriter::new(undo_file);
+        write_script(&mut out, format, &undo)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_sh() {
+        let renames = [
+            Rename {
+                from: "dir/it's here.txt".into(),
+                to: "dir/it_s_here.txt".into(),
+            },
+            Rename {
+                from: "-rf $(x)".into(),
+                to: "rf_x".into(),
+            },
+        ];
+        let mut out = Vec::new();
+        write_sh(&mut out, &renames).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "#!/bin/sh\n",
+                "set -e\n",
+                "if [ -e 'dir/it_s_here.txt' ] || [ -L 'dir/it_s_here.txt' ]; ",
+                "then printf 'target already exists: %s\\n' 'dir/it_s_here.txt' >&2; exit 1; fi\n",
+                "mv -- 'dir/it'\\''s here.txt' 'dir/it_s_here.txt'\n",
+                "if [ -e 'rf_x' ] || [ -L 'rf_x' ]; ",
+                "then printf 'target already exists: %s\\n' 'rf_x' >&2; exit 1; fi\n",
+                "mv -- '-rf $(x)' 'rf_x'\n",
+            )
+        );
+
+        // The script refuses to overwrite existing targets
+        let root = tempfile::TempDir::new().unwrap();
+        std::fs::write(root.path().join("a"), "a").unwrap();
+        std::fs::write(root.path().join("b"), "b").unwrap();
+        let renames = [Rename {
+            from: "a".into(),
+            to: "b".into(),
+        }];
+        let mut script = Vec::new();
+        write_sh(&mut script, &renames).unwrap();
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(std::ffi::OsStr::from_bytes(&script))
+            .current_dir(root.path())
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(output.stderr, b"target already exists: b\n");
+        assert_eq!(std::fs::read(root.path().join("b")).unwrap(), b"b");
+    }
+}
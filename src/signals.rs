@@ -0,0 +1,63 @@
+//! Catching SIGINT and SIGTERM, so that a run can stop between renames
+
+use crate::Result;
+
+use std::os::raw::c_int;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
+use signal_hook::flag;
+
+lazy_static! {
+    /// Set when a caught signal is received, so that a second one terminates
+    /// the process right away
+    static ref CAUGHT: Arc<AtomicBool> = Arc::default();
+
+    /// The last caught signal received, or 0
+    static ref SIGNAL: Arc<AtomicUsize> = Arc::default();
+}
+
+/// Stop SIGINT and SIGTERM from terminating the process, recording them
+/// instead so that `caught()` returns them. Receiving a second signal
+/// terminates the process with the status for it.
+pub fn catch_signals() -> Result<()> {
+    for signal in [SIGINT, SIGTERM] {
+        flag::register_conditional_shutdown(signal, exit_status(signal), Arc::clone(&CAUGHT))?;
+        flag::register(signal, Arc::clone(&CAUGHT))?;
+        flag::register_usize(signal, Arc::clone(&SIGNAL), signal as usize)?;
+    }
+    Ok(())
+}
+
+/// The signal received since `catch_signals()`, if any
+pub fn caught() -> Option<c_int> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal as c_int),
+    }
+}
+
+/// Record that `signal` was received, for interrupts that do not arrive as a
+/// signal, like Ctrl-C typed at a prompt while the terminal is in raw mode
+pub fn record(signal: c_int) {
+    CAUGHT.store(true, Ordering::SeqCst);
+    SIGNAL.store(signal as usize, Ordering::SeqCst);
+}
+
+/// Name of `signal`, for messages
+pub fn name(signal: c_int) -> &'static str {
+    match signal {
+        SIGINT => "SIGINT",
+        SIGTERM => "SIGTERM",
+        _ => "a signal",
+    }
+}
+
+/// Exit status after being stopped by `signal`, like shells report it
+pub fn exit_status(signal: c_int) -> c_int {
+    128 + signal
+}
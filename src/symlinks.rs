@@ -0,0 +1,164 @@
+//! Update symlinks whose targets were renamed
+
+use crate::fs_ext::walk;
+use crate::fs_ext::FSExt;
+use crate::opts::Flags;
+use crate::renames::normalize;
+use crate::renames::Rename;
+use crate::renames::RenameMap;
+use crate::Result;
+
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use rsfs::FileType;
+use rsfs::Metadata;
+
+/// Rewrite the target of a symlink located in `dir` (a directory from before
+/// the renames), so that each renamed component is replaced by its new name.
+/// The form of the target is preserved: relative targets stay relative, and
+/// `.` and `..` components are kept as they are. Returns `None` if no
+/// component of the target was renamed.
+fn retarget(map: &RenameMap, dir: &Path, target: &Path) -> Option<PathBuf> {
+    let mut old_path = dir.to_path_buf();
+    let mut new_target = PathBuf::new();
+    let mut changed = false;
+
+    for component in target.components() {
+        match component {
+            Component::Normal(name) => {
+                old_path.push(name);
+                let new_path = map.forward(&old_path);
+                match new_path.file_name() {
+                    Some(new_name) if new_name != name => {
+                        changed = true;
+                        new_target.push(new_name);
+                    }
+                    _ => new_target.push(name),
+                }
+            }
+            Component::ParentDir => {
+                old_path.pop();
+                new_target.push(component);
+            }
+            Component::RootDir => {
+                old_path = PathBuf::from(component.as_os_str());
+                new_target.push(component);
+            }
+            Component::CurDir | Component::Prefix(_) => new_target.push(component),
+        }
+    }
+
+    changed.then_some(new_target)
+}
+
+/// Update the symlink at `link` (a path from after the renames) if its
+/// target was renamed
+fn update_symlink<FS: FSExt>(
+    fs: &FS,
+    cwd: &Path,
+    map: &RenameMap,
+    link: &Path,
+    flags: &Flags,
+) -> Result<()> {
+    let target = fs.read_link(cwd.join(link))?;
+    let old_dir = match normalize(cwd, link).parent() {
+        Some(parent) => map.backward(parent),
+        None => return Ok(()),
+    };
+    let new_target = match retarget(map, &old_dir, &target) {
+        Some(new_target) => new_target,
+        None => return Ok(()),
+    };
+
+    let prefix = if flags.dry_run {
+        "would update symlink"
+    } else {
+        "update symlink"
+    };
+    println!(
+        "{} '{}' -> '{}'",
+        prefix,
+        link.display(),
+        new_target.display()
+    );
+
+    if !flags.dry_run {
+        fs.remove_file(cwd.join(link))?;
+        fs.symlink(new_target, cwd.join(link))?;
+    }
+    Ok(())
+}
+
+/// After `renames` were performed, find symlinks in the trees rooted at
+/// `roots` (the paths passed on the command line) pointing at renamed
+/// entries, and rewrite their targets to the new names. Subdirectories are
+/// only searched when using `--recursive`.
+pub fn update_symlinks<FS: FSExt>(
+    fs: &FS,
+    cwd: &Path,
+    roots: &[PathBuf],
+    renames: &[Rename],
+    flags: &Flags,
+) -> Result<()> {
+    if renames.is_empty() {
+        return Ok(());
+    }
+
+    let map = RenameMap::new(cwd, renames);
+    for root in roots {
+        let root = map.forward_relative(cwd, root);
+        walk(fs, cwd, &root, flags.recursive, &mut |path, stat| {
+            if stat.file_type().is_symlink() {
+                update_symlink(fs, cwd, &map, path, flags)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retarget() {
+        let renames = [
+            Rename {
+                from: "/r/assets dir/Sprite One.png".into(),
+                to: "/r/assets dir/Sprite_One.png".into(),
+            },
+            Rename {
+                from: "/r/assets dir".into(),
+                to: "/r/assets_dir".into(),
+            },
+        ];
+        let map = RenameMap::new(Path::new("/"), &renames);
+
+        let f = |dir: &str, target: &str| retarget(&map, Path::new(dir), Path::new(target));
+        assert_eq!(
+            f("/r/links", "../assets dir/Sprite One.png"),
+            Some("../assets_dir/Sprite_One.png".into())
+        );
+        assert_eq!(
+            f("/r/links", "./../assets dir"),
+            Some("./../assets_dir".into())
+        );
+        assert_eq!(
+            f("/elsewhere", "/r/assets dir/Sprite One.png"),
+            Some("/r/assets_dir/Sprite_One.png".into())
+        );
+        assert_eq!(
+            f("/r/assets dir", "Sprite One.png"),
+            Some("Sprite_One.png".into())
+        );
+        assert_eq!(
+            f("/r/assets dir", "../assets dir/x"),
+            Some("../assets_dir/x".into())
+        );
+        assert_eq!(f("/r/links", "../other dir/Sprite One.png"), None);
+        assert_eq!(f("/r/links", "Sprite One.png"), None);
+    }
+}
@@ -0,0 +1,561 @@
+//! Full-screen review of a rename plan before applying it
+
+use crate::filename_parts::NamingRules;
+use crate::is_valid_filename;
+use crate::read_children_names;
+use crate::renames::normalize;
+use crate::renames::Rename;
+use crate::signals;
+use crate::unixize_name_in_dir;
+use crate::EntryKind;
+use crate::Result;
+
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+
+use signal_hook::consts::SIGINT;
+
+use ratatui::crossterm::event;
+use ratatui::crossterm::event::Event;
+use ratatui::crossterm::event::KeyCode;
+use ratatui::crossterm::event::KeyEvent;
+use ratatui::crossterm::event::KeyEventKind;
+use ratatui::crossterm::event::KeyModifiers;
+use ratatui::layout::Constraint;
+use ratatui::layout::Layout;
+use ratatui::style::Style;
+use ratatui::style::Stylize;
+use ratatui::widgets::Cell;
+use ratatui::widgets::Paragraph;
+use ratatui::widgets::Row;
+use ratatui::widgets::Table;
+use ratatui::widgets::TableState;
+use ratatui::Frame;
+use rsfs::GenFS;
+
+const HELP: &str = "space: toggle  a: accept shown  d: reject shown  e: edit  /: filter  \
+                    enter: apply  q: quit";
+
+/// A planned rename being reviewed
+struct Entry {
+    rename: Rename,
+
+    /// Target picked by the planner, restored if an edit is undone
+    planned: PathBuf,
+
+    /// Whether the planner had to number the new name to avoid a collision
+    collided: bool,
+
+    accepted: bool,
+
+    /// Whether the accepted new name would clobber another entry
+    conflict: bool,
+}
+
+/// Line of the tree shown to the user
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    /// Directory containing entries, shown for context
+    Dir(PathBuf),
+    /// Index of an entry
+    Entry(usize),
+}
+
+/// What keys typed by the user do
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    Normal,
+    /// Typing the filter
+    Filter,
+    /// Typing a new name for the entry with the given index
+    Edit(usize, String),
+}
+
+/// What to do after the review
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Outcome {
+    Apply,
+    Cancel,
+}
+
+/// State of the review of a plan
+struct Review {
+    cwd: PathBuf,
+    entries: Vec<Entry>,
+
+    /// Indices of `entries`, sorted by old path so that directories come
+    /// before their contents
+    order: Vec<usize>,
+
+    /// Names in the directories containing the entries, before any renames,
+    /// keyed by normalized path
+    existing: HashMap<PathBuf, HashSet<OsString>>,
+
+    /// Only entries whose old or new path contains this are shown
+    filter: String,
+
+    mode: Mode,
+
+    /// Lines currently shown
+    lines: Vec<Line>,
+
+    table: TableState,
+
+    /// Message shown in the status line until the next key
+    message: Option<String>,
+}
+
+impl Review {
+    /// Start reviewing `plan`, reading the directories it renames entries in
+    /// from `fs`. Entries whose new names differ from what `rules` give are
+    /// marked as collided. No entry is accepted at first.
+    fn new<FS: GenFS>(fs: &FS, cwd: &Path, rules: &NamingRules, plan: Vec<Rename>) -> Result<Self> {
+        let mut existing = HashMap::new();
+        let mut entries = Vec::new();
+        for rename in plan {
+            let parent = rename.from.parent().unwrap_or_else(|| Path::new(""));
+            let key = normalize(cwd, parent);
+            let names: &HashSet<OsString> = match existing.entry(key) {
+                hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                hash_map::Entry::Vacant(entry) => {
+                    let names = read_children_names(fs, cwd, parent)?;
+                    entry.insert(names.into_iter().collect())
+                }
+            };
+
+            let old_name = rename.from.file_name().unwrap_or_default();
+            let (kind, _) = EntryKind::inspect(fs, cwd, &rename.from, false)?;
+            let new_name = unixize_name_in_dir(&old_name.to_string_lossy(), kind, rules, |name| {
+                names.contains(name)
+            });
+            let collided = rename.to.file_name() != Some(new_name.as_ref());
+            entries.push(Entry {
+                planned: rename.to.clone(),
+                rename,
+                collided,
+                accepted: false,
+                conflict: false,
+            });
+        }
+
+        let mut order: Vec<usize> = (0..entries.len()).collect();
+        order.sort_by(|&a, &b| entries[a].rename.from.cmp(&entries[b].rename.from));
+
+        let mut review = Self {
+            cwd: cwd.to_path_buf(),
+            entries,
+            order,
+            existing,
+            filter: String::new(),
+            mode: Mode::Normal,
+            lines: Vec::new(),
+            table: TableState::default(),
+            message: None,
+        };
+        review.update_lines();
+        Ok(review)
+    }
+
+    /// Returns `true` if the entry is shown with the current filter
+    fn matches_filter(&self, entry: &Entry) -> bool {
+        let filter = self.filter.to_lowercase();
+        [&entry.rename.from, &entry.rename.to]
+            .iter()
+            .any(|path| path.to_string_lossy().to_lowercase().contains(&filter))
+    }
+
+    /// Recompute the lines shown, after the filter changed
+    fn update_lines(&mut self) {
+        let mut lines = Vec::new();
+        let mut shown = HashSet::new();
+        for &i in &self.order {
+            let entry = &self.entries[i];
+            if !self.matches_filter(entry) {
+                continue;
+            }
+
+            // Show the directories above the entry that are not entries
+            // themselves, or were filtered out
+            let from = &entry.rename.from;
+            let mut dirs: Vec<&Path> = from
+                .ancestors()
+                .skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .collect();
+            dirs.reverse();
+            for dir in dirs {
+                if shown.insert(dir.to_path_buf()) {
+                    lines.push(Line::Dir(dir.to_path_buf()));
+                }
+            }
+            shown.insert(from.clone());
+            lines.push(Line::Entry(i));
+        }
+
+        self.lines = lines;
+        let selected = match self.table.selected() {
+            _ if self.lines.is_empty() => None,
+            Some(selected) => Some(selected.min(self.lines.len() - 1)),
+            None => Some(0),
+        };
+        self.table.select(selected);
+    }
+
+    /// Flag the accepted entries whose new names would clobber an entry,
+    /// because another accepted entry has the same new name, or because an
+    /// entry with that name exists and is not renamed away before
+    fn update_conflicts(&mut self) {
+        let cwd = &self.cwd;
+        let mut targets: HashMap<PathBuf, usize> = HashMap::new();
+        let mut freed = HashMap::new();
+        for (i, entry) in self.entries.iter().enumerate() {
+            if entry.accepted {
+                *targets.entry(normalize(cwd, &entry.rename.to)).or_default() += 1;
+                freed.insert(normalize(cwd, &entry.rename.from), i);
+            }
+        }
+
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            let to = normalize(cwd, &entry.rename.to);
+            let exists = match (to.parent(), to.file_name()) {
+                (Some(parent), Some(name)) => self
+                    .existing
+                    .get(parent)
+                    .is_some_and(|names| names.contains(name)),
+                _ => false,
+            };
+            let freed_before = freed.get(&to).is_some_and(|&j| j < i);
+            entry.conflict = entry.accepted && (targets[&to] > 1 || (exists && !freed_before));
+        }
+    }
+
+    /// Index of the entry on the selected line, if it is an entry
+    fn selected_entry(&self) -> Option<usize> {
+        match self.lines.get(self.table.selected()?)? {
+            Line::Entry(i) => Some(*i),
+            Line::Dir(_) => None,
+        }
+    }
+
+    /// Accept or reject all entries that are shown
+    fn set_shown_accepted(&mut self, accepted: bool) {
+        for line in &self.lines {
+            if let Line::Entry(i) = line {
+                self.entries[*i].accepted = accepted;
+            }
+        }
+        self.update_conflicts();
+    }
+
+    /// Set the new name of entry `i` to `name`, which was typed by the user
+    fn set_name(&mut self, i: usize, name: &str) {
+        if !is_valid_filename(name) {
+            self.message = Some(format!("invalid filename '{}'", name));
+            return;
+        }
+
+        let entry = &mut self.entries[i];
+        if entry.rename.from.file_name() == Some(name.as_ref()) {
+            // Keeping the old name means not renaming
+            entry.rename.to = entry.planned.clone();
+            entry.accepted = false;
+        } else {
+            entry.rename.to = entry.rename.from.with_file_name(name);
+            entry.accepted = true;
+        }
+        self.update_conflicts();
+    }
+
+    /// Handle a key typed by the user, returning what to do if the review is
+    /// over
+    fn handle_key(&mut self, key: KeyEvent) -> Option<Outcome> {
+        self.message = None;
+        // Ctrl-C is read as a key in raw mode, so it is recorded as if SIGINT
+        // was caught, and other modified keys are not commands or text
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            signals::record(SIGINT);
+            return Some(Outcome::Cancel);
+        }
+        if key
+            .modifiers
+            .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+        {
+            return None;
+        }
+        match &mut self.mode {
+            Mode::Filter => {
+                match key.code {
+                    KeyCode::Char(c) => self.filter.push(c),
+                    KeyCode::Backspace => {
+                        self.filter.pop();
+                    }
+                    KeyCode::Enter => self.mode = Mode::Normal,
+                    KeyCode::Esc => {
+                        self.filter.clear();
+                        self.mode = Mode::Normal;
+                    }
+                    _ => (),
+                }
+                self.update_lines();
+            }
+            Mode::Edit(i, name) => match key.code {
+                KeyCode::Char(c) => name.push(c),
+                KeyCode::Backspace => {
+                    name.pop();
+                }
+                KeyCode::Enter => {
+                    let (i, name) = (*i, name.clone());
+                    self.mode = Mode::Normal;
+                    self.set_name(i, &name);
+                }
+                KeyCode::Esc => self.mode = Mode::Normal,
+                _ => (),
+            },
+            Mode::Normal => match key.code {
+                KeyCode::Up | KeyCode::Char('k') => self.table.select_previous(),
+                KeyCode::Down | KeyCode::Char('j') => self.table.select_next(),
+                KeyCode::PageUp => self.table.scroll_up_by(20),
+                KeyCode::PageDown => self.table.scroll_down_by(20),
+                KeyCode::Home | KeyCode::Char('g') => self.table.select_first(),
+                KeyCode::End | KeyCode::Char('G') => self.table.select_last(),
+                KeyCode::Char(' ') => {
+                    if let Some(i) = self.selected_entry() {
+                        self.entries[i].accepted = !self.entries[i].accepted;
+                        self.update_conflicts();
+                    }
+                }
+                KeyCode::Char('a') => self.set_shown_accepted(true),
+                KeyCode::Char('d') => self.set_shown_accepted(false),
+                KeyCode::Char('e') => {
+                    if let Some(i) = self.selected_entry() {
+                        let name = self.entries[i].rename.to.file_name().unwrap_or_default();
+                        self.mode = Mode::Edit(i, name.to_string_lossy().into_owned());
+                    }
+                }
+                KeyCode::Char('/') => self.mode = Mode::Filter,
+                KeyCode::Enter => {
+                    if self.entries.iter().any(|entry| entry.conflict) {
+                        self.message = Some("resolve the conflicts marked with '!' first".into());
+                    } else {
+                        return Some(Outcome::Apply);
+                    }
+                }
+                KeyCode::Char('q') | KeyCode::Esc => return Some(Outcome::Cancel),
+                _ => (),
+            },
+        }
+        None
+    }
+
+    /// Draw the tree of lines and the status line
+    fn draw(&mut self, frame: &mut Frame) {
+        let [table_area, status_area] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+
+        let rows = self.lines.iter().map(|line| {
+            let (path, entry) = match line {
+                Line::Dir(dir) => (dir, None),
+                Line::Entry(i) => (&self.entries[*i].rename.from, Some(&self.entries[*i])),
+            };
+            let depth = path.components().count().saturating_sub(1);
+            let indent = "  ".repeat(depth);
+            let old_name = path
+                .file_name()
+                .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy());
+
+            match entry {
+                None => Row::new(vec![
+                    Cell::from(""),
+                    Cell::from(format!("{}{}/", indent, old_name)),
+                    Cell::from(""),
+                ])
+                .dim(),
+                Some(entry) => {
+                    let mark = match (entry.accepted, entry.conflict) {
+                        (_, true) => "[!]",
+                        (true, false) => "[x]",
+                        (false, false) => "[ ]",
+                    };
+                    let new_name = entry.rename.to.file_name().unwrap_or_default();
+                    let style = if entry.conflict {
+                        Style::new().red()
+                    } else if entry.collided {
+                        Style::new().yellow()
+                    } else {
+                        Style::new()
+                    };
+                    Row::new(vec![
+                        Cell::from(mark),
+                        Cell::from(format!("{}{}", indent, old_name)),
+                        Cell::from(new_name.to_string_lossy().into_owned()),
+                    ])
+                    .style(style)
+                }
+            }
+        });
+        let widths = [
+            Constraint::Length(3),
+            Constraint::Percentage(50),
+            Constraint::Percentage(50),
+        ];
+        let table = Table::new(rows, widths)
+            .header(Row::new(vec!["", "old name", "new name"]).bold())
+            .row_highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(table, table_area, &mut self.table);
+
+        let accepted = self.entries.iter().filter(|entry| entry.accepted).count();
+        let status = match (&self.mode, &self.message) {
+            (Mode::Filter, _) => format!("filter: {}", self.filter),
+            (Mode::Edit(_, name), _) => format!("new name: {}", name),
+            (Mode::Normal, Some(message)) => message.clone(),
+            (Mode::Normal, None) => {
+                format!("{}/{} accepted  {}", accepted, self.entries.len(), HELP)
+            }
+        };
+        frame.render_widget(Paragraph::new(status), status_area);
+    }
+
+    /// The accepted renames, in the order of the plan
+    fn accepted(self) -> Vec<Rename> {
+        self.entries
+            .into_iter()
+            .filter(|entry| entry.accepted)
+            .map(|entry| entry.rename)
+            .collect()
+    }
+}
+
+/// Let the user review `plan` in a full-screen interface, returning the
+/// renames they accepted, possibly with edited new names. Nothing is accepted
+/// if they quit.
+pub fn review<FS: GenFS>(
+    fs: &FS,
+    cwd: &Path,
+    rules: &NamingRules,
+    plan: Vec<Rename>,
+) -> Result<Vec<Rename>> {
+    if plan.is_empty() {
+        return Ok(plan);
+    }
+
+    let mut review = Review::new(fs, cwd, rules, plan)?;
+    let mut terminal = ratatui::try_init()?;
+    let outcome = loop {
+        if let Err(err) = terminal.draw(|frame| review.draw(frame)) {
+            break Err(err);
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                if let Some(outcome) = review.handle_key(key) {
+                    break Ok(outcome);
+                }
+            }
+            Ok(_) => (),
+            Err(err) => break Err(err),
+        }
+    };
+    ratatui::try_restore()?;
+
+    match outcome? {
+        Outcome::Apply => Ok(review.accepted()),
+        Outcome::Cancel => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(review: &mut Review, keys: &str) -> Option<Outcome> {
+        let mut outcome = None;
+        for c in keys.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                '\x1b' => KeyCode::Esc,
+                '\x08' => KeyCode::Backspace,
+                c => KeyCode::Char(c),
+            };
+            outcome = review.handle_key(KeyEvent::new(code, KeyModifiers::NONE));
+        }
+        outcome
+    }
+
+    #[test]
+    fn test_review() {
+        let fs = rsfs::mem::FS::new();
+        let cwd = Path::new("/");
+        for path in ["/d d", "/d d/a b", "/d d/a  b", "/d d/a_b_000", "/x"] {
+            if path.ends_with('d') {
+                fs.create_dir(path).unwrap();
+            } else {
+                fs.create_file(path).unwrap();
+            }
+        }
+        let rename = |from: &str, to: &str| Rename {
+            from: from.into(),
+            to: to.into(),
+        };
+        let plan = vec![
+            rename("d d/a  b", "d d/a_b"),
+            rename("d d/a b", "d d/a_b_001"),
+            rename("d d", "d_d"),
+        ];
+        let rules = NamingRules::default();
+        let mut review = Review::new(&fs, cwd, &rules, plan).unwrap();
+
+        // Entries are shown as a tree, sorted by old path
+        assert_eq!(
+            review.lines,
+            [Line::Entry(2), Line::Entry(0), Line::Entry(1)]
+        );
+        assert!(!review.entries[0].collided);
+        assert!(review.entries[1].collided);
+
+        // Filtering shows the directories above matching entries
+        press(&mut review, "/a  b\n");
+        assert_eq!(review.lines, [Line::Dir("d d".into()), Line::Entry(0)]);
+        press(&mut review, "/\x1b");
+        assert_eq!(review.lines.len(), 3);
+
+        // Modified keys are neither commands nor text
+        for c in ['a', 'd'] {
+            review.handle_key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL));
+        }
+        press(&mut review, "/");
+        review.handle_key(KeyEvent::new(KeyCode::Char('x'), KeyModifiers::ALT));
+        assert_eq!(review.filter, "");
+        press(&mut review, "\x1b");
+        assert!(review.entries.iter().all(|entry| !entry.accepted));
+
+        // Accept everything, then reject the directory
+        press(&mut review, "a ");
+        let accepted: Vec<bool> = review.entries.iter().map(|entry| entry.accepted).collect();
+        assert_eq!(accepted, [true, true, false]);
+
+        // Editing to an existing name is a conflict, which blocks applying
+        press(&mut review, "je\x08\x08\x08a_b_000\n");
+        assert!(review.entries[0].conflict);
+        assert_eq!(press(&mut review, "\n"), None);
+        assert!(review.message.is_some());
+
+        // Editing to a free name resolves it
+        press(&mut review, "e");
+        press(&mut review, &"\x08".repeat("a_b_000".len()));
+        press(&mut review, "c\n");
+        assert!(!review.entries[0].conflict);
+        assert_eq!(press(&mut review, "\n"), Some(Outcome::Apply));
+
+        let accepted = review.accepted();
+        assert_eq!(
+            accepted,
+            [
+                rename("d d/a  b", "d d/c"),
+                rename("d d/a b", "d d/a_b_001")
+            ]
+        );
+    }
+}
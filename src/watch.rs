@@ -0,0 +1,293 @@
+//! Watch a directory with inotify and unixize new entries as they appear
+
+use crate::fs_ext::walk;
+use crate::opts::WatchOpts;
+use crate::unixize;
+use crate::Result;
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use inotify::EventMask;
+use inotify::EventOwned;
+use inotify::Inotify;
+use inotify::WatchDescriptor;
+use inotify::WatchMask;
+use rsfs::Metadata;
+
+/// How often to check for entries that are ready while some are pending
+const TICK: Duration = Duration::from_millis(50);
+
+/// How long to wait for a created file that was never closed before renaming
+/// it anyway, for example if it was created as a hard link
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Entry waiting to be unixized
+#[derive(Debug)]
+struct Pending {
+    /// Time of the last event about the entry
+    last_event: Instant,
+
+    /// Whether the entry is a file that was created and is still open for
+    /// writing
+    writing: bool,
+}
+
+/// State of `unf watch`
+struct Watcher<'a> {
+    cwd: &'a Path,
+    opts: &'a WatchOpts,
+    inotify: Inotify,
+
+    /// Directory watched by each watch descriptor
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+
+    /// Entries waiting until they stop changing
+    pending: HashMap<PathBuf, Pending>,
+}
+
+impl<'a> Watcher<'a> {
+    /// Watch `dir`, and the directories below it when using `--recursive`.
+    /// Directories that are already watched are mapped to their current path,
+    /// which keeps the paths up to date after directories are renamed.
+    fn add_watches(&mut self, dir: &Path) -> Result<()> {
+        let mask = WatchMask::CREATE
+            | WatchMask::MOVED_TO
+            | WatchMask::MOVED_FROM
+            | WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::ONLYDIR
+            | WatchMask::DONT_FOLLOW;
+
+        let mut dirs = Vec::new();
+        walk(
+            &rsfs::disk::FS,
+            self.cwd,
+            dir,
+            self.opts.flags.recursive,
+            &mut |path, stat| {
+                if stat.is_dir() {
+                    dirs.push(path.to_path_buf());
+                }
+                Ok(())
+            },
+        )?;
+        for dir in dirs {
+            let wd = self.inotify.watches().add(self.cwd.join(&dir), mask)?;
+            self.dirs.insert(wd, dir);
+        }
+        Ok(())
+    }
+
+    /// Queue all entries in the watched directory, after events were lost
+    fn rescan(&mut self) -> Result<()> {
+        eprintln!(
+            "unf: warning: too many events, rescanning '{}'",
+            self.opts.dir.display()
+        );
+        for name in crate::read_children_names(&rsfs::disk::FS, self.cwd, &self.opts.dir)? {
+            self.pending.insert(
+                self.opts.dir.join(name),
+                Pending {
+                    last_event: Instant::now(),
+                    writing: false,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Update the pending entries according to `event`
+    fn handle_event(&mut self, event: EventOwned) -> Result<()> {
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            return self.rescan();
+        }
+        if event.mask.contains(EventMask::IGNORED) {
+            self.dirs.remove(&event.wd);
+            return Ok(());
+        }
+
+        let path = match (self.dirs.get(&event.wd), event.name) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => return Ok(()),
+        };
+        let now = Instant::now();
+        let is_dir = event.mask.contains(EventMask::ISDIR);
+
+        if event.mask.contains(EventMask::MOVED_FROM) {
+            self.pending.remove(&path);
+        } else if event
+            .mask
+            .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+        {
+            if is_dir && self.opts.flags.recursive {
+                // Entries created inside before the watch was added are
+                // renamed along with the directory
+                if let Err(err) = self.add_watches(&path) {
+                    eprintln!("unf: warning: cannot watch '{}': {}", path.display(), err);
+                }
+            }
+            let writing = event.mask.contains(EventMask::CREATE) && !is_dir;
+            self.pending.insert(
+                path,
+                Pending {
+                    last_event: now,
+                    writing,
+                },
+            );
+        } else if let Some(pending) = self.pending.get_mut(&path) {
+            // Only entries that are new are renamed, not existing ones that
+            // are modified
+            pending.last_event = now;
+            if event.mask.contains(EventMask::CLOSE_WRITE) {
+                pending.writing = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove and return the pending entries that stopped changing, sorted,
+    /// and without those below other ready directories when recursing. When
+    /// recursing, directories are held back while entries below them are
+    /// still changing, since they would be renamed along with them.
+    fn take_ready(&mut self) -> Vec<PathBuf> {
+        let debounce = Duration::from_millis(self.opts.debounce);
+        let now = Instant::now();
+        let is_ready = |pending: &Pending| {
+            let quiet = now.duration_since(pending.last_event);
+            quiet >= debounce && (!pending.writing || quiet >= WRITE_TIMEOUT)
+        };
+        let waiting: Vec<&PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| !is_ready(pending))
+            .map(|(path, _)| path)
+            .collect();
+        let mut ready: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(path, pending)| {
+                is_ready(pending)
+                    && !(self.opts.flags.recursive
+                        && waiting.iter().any(|waiting| waiting.starts_with(path)))
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in &ready {
+            self.pending.remove(path);
+        }
+
+        ready.sort();
+        if self.opts.flags.recursive {
+            let mut outer: Vec<PathBuf> = Vec::new();
+            for path in ready {
+                if !outer.last().is_some_and(|last| path.starts_with(last)) {
+                    outer.push(path);
+                }
+            }
+            ready = outer;
+        }
+        ready.retain(|path| self.cwd.join(path).symlink_metadata().is_ok());
+        ready
+    }
+
+    /// Wait for inotify events, without blocking if entries are pending
+    fn read_events(&mut self, buffer: &mut [u8]) -> Result<Vec<EventOwned>> {
+        let events = if self.pending.is_empty() {
+            self.inotify.read_events_blocking(buffer)?
+        } else {
+            thread::sleep(TICK);
+            match self.inotify.read_events(buffer) {
+                Ok(events) => events,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Vec::new()),
+                Err(err) => return Err(err.into()),
+            }
+        };
+        Ok(events.map(|event| event.to_owned()).collect())
+    }
+}
+
+/// Watch the directory in `opts`, unixizing entries created in or moved into
+/// it once they stop changing. Files created in the directory are only
+/// renamed after they are closed. Runs until interrupted.
+pub fn watch(cwd: &Path, opts: &WatchOpts) -> Result<()> {
+    if opts.flags.tui {
+        return Err("--tui cannot be used with watch".into());
+    }
+    if opts.flags.emit_script.is_some() {
+        return Err("--emit-script cannot be used with watch".into());
+    }
+    let mut flags = opts.flags.clone();
+    flags.force = !flags.dry_run;
+
+    let mut watcher = Watcher {
+        cwd,
+        opts,
+        inotify: Inotify::init()?,
+        dirs: HashMap::new(),
+        pending: HashMap::new(),
+    };
+    watcher.add_watches(&opts.dir)?;
+
+    let mut buffer = [0; 4096];
+    loop {
+        for event in watcher.read_events(&mut buffer)? {
+            watcher.handle_event(event)?;
+        }
+
+        let ready = watcher.take_ready();
+        if !ready.is_empty() {
+            // Keep watching after errors, since the entries may have changed
+            // in the meantime
+            if let Err(err) = unixize(cwd, &ready, &flags) {
+                eprintln!("unf: error: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_take_ready() {
+        let root = TempDir::new().unwrap();
+        let cwd = root.path();
+        std::fs::create_dir_all(cwd.join("drop/My Dir")).unwrap();
+        std::fs::File::create(cwd.join("drop/My Dir/a b.txt")).unwrap();
+
+        let opts = WatchOpts::parse_from(["watch", "-r", "--debounce", "100", "drop"]);
+        let mut watcher = Watcher {
+            cwd,
+            opts: &opts,
+            inotify: Inotify::init().unwrap(),
+            dirs: HashMap::new(),
+            pending: HashMap::new(),
+        };
+        let quiet = Instant::now() - Duration::from_secs(1);
+        let dir = PathBuf::from("drop/My Dir");
+        let file = dir.join("a b.txt");
+        for (path, writing) in [(&dir, false), (&file, true)] {
+            let pending = Pending {
+                last_event: quiet,
+                writing,
+            };
+            watcher.pending.insert(path.clone(), pending);
+        }
+
+        // The directory waits for the file still being written in it
+        assert!(watcher.take_ready().is_empty());
+        watcher.pending.get_mut(&file).unwrap().writing = false;
+        assert_eq!(watcher.take_ready(), [dir]);
+        assert!(watcher.pending.is_empty());
+    }
+}
@@ -2,7 +2,9 @@ use std::collections::BTreeSet;
 use std::ffi::OsStr;
 use std::fs;
 use std::io;
+use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -191,3 +193,1255 @@ fn integration_test() {
         ],
     );
 }
+
+#[test]
+fn entry_type_test() {
+    run_unf(
+        ".",
+        &["-rf", "--type", "d", "My Files"],
+        "",
+        concat!(
+            "rename 'My Files/Sub Dir' -> 'My Files/Sub_Dir'\n",
+            "rename 'My Files' -> 'My_Files'\n",
+        ),
+        "",
+        &["My Files", "My Files/Cool Photo.JPG", "My Files/Sub Dir"],
+        &["My_Files", "My_Files/Cool Photo.JPG", "My_Files/Sub_Dir"],
+    );
+    run_unf(
+        ".",
+        &["-rf", "--type", "f", "My Files"],
+        "",
+        "rename 'My Files/Cool Photo.JPG' -> 'My Files/Cool_Photo.JPG'\n",
+        "",
+        &["My Files", "My Files/Cool Photo.JPG", "My Files/Sub Dir"],
+        &["My Files", "My Files/Cool_Photo.JPG", "My Files/Sub Dir"],
+    );
+}
+
+#[test]
+fn special_file_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    let _socket = std::os::unix::net::UnixListener::bind(root.join("my socket")).unwrap();
+    std::os::unix::fs::symlink("my socket", root.join("my link")).unwrap();
+
+    let unf = |args: &[&str]| {
+        assert_cmd::Command::cargo_bin("unf")
+            .unwrap()
+            .current_dir(root)
+            .args(args)
+            .assert()
+            .success()
+    };
+
+    unf(&["-f", "my socket"])
+        .stdout("")
+        .stderr("unf: warning: skipping special file 'my socket'\n");
+    unf(&["-f", "--special", "skip", "my socket"])
+        .stdout("")
+        .stderr("");
+    unf(&["-f", "--type", "l", "my socket", "my link"])
+        .stdout("rename 'my link' -> 'my_link'\n")
+        .stderr("");
+    unf(&["-f", "--special", "rename", "my socket"])
+        .stdout("rename 'my socket' -> 'my_socket'\n")
+        .stderr("");
+    assert!(root.join("my_socket").symlink_metadata().is_ok());
+    assert!(root.join("my_link").symlink_metadata().is_ok());
+}
+
+#[test]
+fn symlink_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    fs::create_dir_all(root.join("a b")).unwrap();
+    fs::create_dir_all(root.join("other dir")).unwrap();
+    fs::File::create(root.join("other dir/x y.txt")).unwrap();
+    std::os::unix::fs::symlink("../other dir", root.join("a b/my link")).unwrap();
+    std::os::unix::fs::symlink(".", root.join("a b/loop link")).unwrap();
+
+    let unf = |args: &[&str]| {
+        assert_cmd::Command::cargo_bin("unf")
+            .unwrap()
+            .current_dir(root)
+            .args(args)
+            .assert()
+            .success()
+    };
+
+    unf(&["-rdL", "a b"])
+        .stdout(concat!(
+            "would rename 'a b/loop link' -> 'a b/loop_link'\n",
+            "would rename 'a b/my link/x y.txt' -> 'a b/my link/x_y.txt'\n",
+            "would rename 'a b/my link' -> 'a b/my_link'\n",
+            "would rename 'a b' -> 'a_b'\n",
+        ))
+        .stderr("unf: warning: not descending into symlink loop 'a b/loop link'\n");
+    unf(&["-rf", "a b"])
+        .stdout(concat!(
+            "rename 'a b/loop link' -> 'a b/loop_link'\n",
+            "rename 'a b/my link' -> 'a b/my_link'\n",
+            "rename 'a b' -> 'a_b'\n",
+        ))
+        .stderr("");
+    assert!(root.join("other dir/x y.txt").exists());
+    assert_eq!(
+        fs::read_link(root.join("a_b/my_link")).unwrap(),
+        Path::new("../other dir")
+    );
+    unf(&["-rfL", "a_b"])
+        .stdout("rename 'a_b/my_link/x y.txt' -> 'a_b/my_link/x_y.txt'\n")
+        .stderr("unf: warning: not descending into symlink loop 'a_b/loop_link'\n");
+    assert!(root.join("other dir/x_y.txt").exists());
+}
+
+#[test]
+fn update_symlinks_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path().canonicalize().unwrap();
+    let root = root.as_path();
+    fs::create_dir_all(root.join("assets dir")).unwrap();
+    fs::create_dir_all(root.join("links")).unwrap();
+    fs::File::create(root.join("assets dir/Sprite One.png")).unwrap();
+    std::os::unix::fs::symlink("../assets dir/Sprite One.png", root.join("links/rel")).unwrap();
+    std::os::unix::fs::symlink(
+        root.join("assets dir/Sprite One.png"),
+        root.join("links/abs"),
+    )
+    .unwrap();
+
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["-rf", "--update-symlinks", "assets dir", "links"])
+        .assert()
+        .success()
+        .stdout(format!(
+            concat!(
+                "rename 'assets dir/Sprite One.png' -> 'assets dir/Sprite_One.png'\n",
+                "rename 'assets dir' -> 'assets_dir'\n",
+                "update symlink 'links/abs' -> '{}/assets_dir/Sprite_One.png'\n",
+                "update symlink 'links/rel' -> '../assets_dir/Sprite_One.png'\n",
+            ),
+            root.display()
+        ))
+        .stderr("");
+
+    assert_eq!(
+        fs::read_link(root.join("links/rel")).unwrap(),
+        Path::new("../assets_dir/Sprite_One.png")
+    );
+    assert!(root.join("links/rel").exists());
+    assert!(root.join("links/abs").exists());
+}
+
+#[test]
+fn git_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .current_dir(root)
+            .args(["-c", "user.name=unf", "-c", "user.email=unf@example.com"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+
+    fs::create_dir_all(root.join("My Dir")).unwrap();
+    fs::File::create(root.join("My Dir/Tracked File.txt")).unwrap();
+    fs::File::create(root.join("Untracked File.txt")).unwrap();
+    git(&["init", "-q"]);
+    git(&["add", "My Dir"]);
+    git(&["commit", "-qm", "init"]);
+
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["-rf", "--git", "My Dir", "Untracked File.txt"])
+        .assert()
+        .success()
+        .stdout(concat!(
+            "rename 'My Dir/Tracked File.txt' -> 'My Dir/Tracked_File.txt'\n",
+            "rename 'My Dir' -> 'My_Dir'\n",
+            "rename 'Untracked File.txt' -> 'Untracked_File.txt'\n",
+        ))
+        .stderr("");
+
+    assert_eq!(
+        git(&["status", "--porcelain"]),
+        b"R  \"My Dir/Tracked File.txt\" -> My_Dir/Tracked_File.txt\n?? Untracked_File.txt\n"
+    );
+
+    // Files reached through a symlink to the working tree are renamed
+    // through git too
+    git(&["commit", "-qm", "rename"]);
+    fs::File::create(root.join("c d.txt")).unwrap();
+    git(&["add", "c d.txt"]);
+    git(&["commit", "-qm", "add"]);
+    let outside = TempDir::new().unwrap();
+    std::os::unix::fs::symlink(root, outside.path().join("glink")).unwrap();
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(outside.path())
+        .args(["-f", "--git", "glink/c d.txt"])
+        .assert()
+        .success()
+        .stdout("rename 'glink/c d.txt' -> 'glink/c_d.txt'\n")
+        .stderr("");
+    assert_eq!(
+        git(&["status", "--porcelain"]),
+        b"R  \"c d.txt\" -> c_d.txt\n?? Untracked_File.txt\n"
+    );
+
+    // Renames through git are rolled back through git too
+    git(&["commit", "-qm", "rename again"]);
+    fs::create_dir_all(root.join("e f")).unwrap();
+    fs::File::create(root.join("e f/g h.txt")).unwrap();
+    git(&["add", "e f"]);
+    git(&["commit", "-qm", "add dir"]);
+    fs::create_dir_all(root.join("z")).unwrap();
+    fs::File::create(root.join("z").join("😀".repeat(60))).unwrap();
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["-rf", "--git", "--transactional", "e f", "z"])
+        .assert()
+        .failure()
+        .stdout(concat!(
+            "rename 'e f/g h.txt' -> 'e f/g_h.txt'\n",
+            "rename 'e f' -> 'e_f'\n",
+            "roll back 'e_f' -> 'e f'\n",
+            "roll back 'e f/g_h.txt' -> 'e f/g h.txt'\n",
+        ))
+        .stderr("unf: error: File name too long (os error 36); rolled back 2 renames\n");
+    assert_eq!(
+        git(&["status", "--porcelain"]),
+        b"?? Untracked_File.txt\n?? z/\n"
+    );
+}
+
+#[test]
+fn rewrite_refs_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    fs::create_dir_all(root.join("site/Sub Dir")).unwrap();
+    fs::File::create(root.join("site/My Photo.png")).unwrap();
+    fs::write(
+        root.join("site/index.md"),
+        "![photo](My%20Photo.png)\n[page](<Sub Dir/Page One.md>)\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("site/Sub Dir/Page One.md"),
+        "[home](../index.md) ![photo](../My%20Photo.png)\n",
+    )
+    .unwrap();
+
+    let unf = |args: &[&str]| {
+        assert_cmd::Command::cargo_bin("unf")
+            .unwrap()
+            .current_dir(root)
+            .args(args)
+            .assert()
+            .success()
+    };
+
+    unf(&["-rd", "--rewrite-refs", "*.md", "site"])
+        .stdout(concat!(
+            "would rename 'site/My Photo.png' -> 'site/My_Photo.png'\n",
+            "would rename 'site/Sub Dir/Page One.md' -> 'site/Sub Dir/Page_One.md'\n",
+            "would rename 'site/Sub Dir' -> 'site/Sub_Dir'\n",
+            "would update references in 'site/Sub_Dir/Page_One.md'\n",
+            "would update references in 'site/index.md'\n",
+        ))
+        .stderr("");
+    unf(&["-rf", "--rewrite-refs", "*.md", "site"])
+        .stdout(concat!(
+            "rename 'site/My Photo.png' -> 'site/My_Photo.png'\n",
+            "rename 'site/Sub Dir/Page One.md' -> 'site/Sub Dir/Page_One.md'\n",
+            "rename 'site/Sub Dir' -> 'site/Sub_Dir'\n",
+            "update references in 'site/Sub_Dir/Page_One.md'\n",
+            "update references in 'site/index.md'\n",
+        ))
+        .stderr("");
+
+    assert_eq!(
+        fs::read_to_string(root.join("site/index.md")).unwrap(),
+        "![photo](My_Photo.png)\n[page](<Sub_Dir/Page_One.md>)\n"
+    );
+    assert_eq!(
+        fs::read_to_string(root.join("site/Sub_Dir/Page_One.md")).unwrap(),
+        "[home](../index.md) ![photo](../My_Photo.png)\n"
+    );
+}
+
+#[test]
+fn jobs_test() {
+    run_unf(
+        ".",
+        &["-rf", "-j", "4", "My Files/", "My Folder"],
+        "",
+        concat!(
+            "rename 'My Files/Sub Dir/a  b.txt' -> 'My Files/Sub Dir/a_b.txt'\n",
+            "rename 'My Files/Sub Dir/a b.txt' -> 'My Files/Sub Dir/a_b_000.txt'\n",
+            "rename 'My Files/Sub Dir' -> 'My Files/Sub_Dir'\n",
+            "rename 'My Files/Wow Cool Photo.JPG' -> 'My Files/Wow_Cool_Photo.JPG'\n",
+            "rename 'My Files/' -> 'My_Files'\n",
+            "rename 'My Folder' -> 'My_Folder'\n",
+        ),
+        "",
+        &[
+            "My Folder",
+            "My Files",
+            "My Files/Sub Dir",
+            "My Files/Sub Dir/a b.txt",
+            "My Files/Sub Dir/a  b.txt",
+            "My Files/Wow Cool Photo.JPG",
+        ],
+        &[
+            "My_Folder",
+            "My_Files",
+            "My_Files/Sub_Dir",
+            "My_Files/Sub_Dir/a_b.txt",
+            "My_Files/Sub_Dir/a_b_000.txt",
+            "My_Files/Wow_Cool_Photo.JPG",
+        ],
+    );
+    run_unf(
+        ".",
+        &[
+            "-f",
+            "-j",
+            "0",
+            "--",
+            "--fake-flag.txt",
+            "fake-flag.txt",
+            " fake-flag.txt",
+        ],
+        "",
+        concat!(
+            "rename '--fake-flag.txt' -> 'fake-flag_000.txt'\n",
+            "rename ' fake-flag.txt' -> 'fake-flag_001.txt'\n",
+        ),
+        "",
+        &["--fake-flag.txt", "fake-flag.txt", " fake-flag.txt"],
+        &["fake-flag.txt", "fake-flag_000.txt", "fake-flag_001.txt"],
+    );
+}
+
+#[test]
+fn dry_run_test() {
+    let paths = [
+        "My Files",
+        "My Files/Sub Dir",
+        "My Files/Sub Dir/a b.txt",
+        "My Files/Sub Dir/a  b.txt",
+        "My Files/Sub Dir/a_b.txt",
+        "My_Files",
+    ];
+    run_unf(
+        ".",
+        &["-rd", "My Files", "My_Files"],
+        "",
+        concat!(
+            "would rename 'My Files/Sub Dir/a  b.txt' -> 'My Files/Sub Dir/a_b_000.txt'\n",
+            "would rename 'My Files/Sub Dir/a b.txt' -> 'My Files/Sub Dir/a_b_001.txt'\n",
+            "would rename 'My Files/Sub Dir' -> 'My Files/Sub_Dir'\n",
+            "would rename 'My Files' -> 'My_Files_000'\n",
+        ),
+        "",
+        &paths,
+        &paths,
+    );
+}
+
+#[test]
+fn merge_dirs_test() {
+    let paths = [
+        "My Files",
+        "My Files/a b.txt",
+        "My Files/sub",
+        "My Files/sub/c.txt",
+        "My_Files",
+        "My_Files/a_b.txt",
+        "My_Files/sub",
+    ];
+    let stdout = |prefix: &str| {
+        [
+            "rename 'My Files/a b.txt' -> 'My Files/a_b.txt'\n",
+            "merge 'My Files' -> 'My_Files'\n",
+            "rename 'My Files/a_b.txt' -> 'My_Files/a_b_000.txt'\n",
+            "rename 'My Files/sub/c.txt' -> 'My_Files/sub/c.txt'\n",
+        ]
+        .map(|line| format!("{}{}", prefix, line))
+        .concat()
+    };
+    run_unf(
+        ".",
+        &["-rd", "--merge-dirs", "My Files"],
+        "".to_string(),
+        stdout("would "),
+        "".to_string(),
+        &paths[..],
+        &paths[..],
+    );
+    run_unf(
+        ".",
+        &["-rf", "--merge-dirs", "My Files"],
+        "".to_string(),
+        stdout(""),
+        "".to_string(),
+        &paths[..],
+        &[
+            "My_Files",
+            "My_Files/a_b.txt",
+            "My_Files/a_b_000.txt",
+            "My_Files/sub",
+            "My_Files/sub/c.txt",
+        ][..],
+    );
+}
+
+#[test]
+fn markers_test() {
+    // Markers are kept as part of the name by default
+    run_unf(
+        ".",
+        &["-f", "IMG (1).jpg", "IMG.jpg", "chapter-1.md", "chapter.md"],
+        "",
+        "rename 'IMG (1).jpg' -> 'IMG_1.jpg'\n",
+        "",
+        ["IMG (1).jpg", "IMG.jpg", "chapter-1.md", "chapter.md"],
+        ["IMG_1.jpg", "IMG.jpg", "chapter-1.md", "chapter.md"],
+    );
+
+    // A bare `-1` is only a marker next to the unmarked name
+    run_unf(
+        ".",
+        &[
+            "-f",
+            "--normalize-markers",
+            "IMG (1).jpg",
+            "IMG.jpg",
+            "Notes - Copy (2).txt",
+            "chapter-1.md",
+            "setup-1.exe",
+            "setup.exe",
+        ],
+        "",
+        concat!(
+            "rename 'IMG (1).jpg' -> 'IMG_000.jpg'\n",
+            "rename 'Notes - Copy (2).txt' -> 'Notes_001.txt'\n",
+            "rename 'setup-1.exe' -> 'setup_000.exe'\n",
+        ),
+        "",
+        [
+            "IMG (1).jpg",
+            "IMG.jpg",
+            "Notes - Copy (2).txt",
+            "chapter-1.md",
+            "setup-1.exe",
+            "setup.exe",
+        ],
+        [
+            "IMG_000.jpg",
+            "IMG.jpg",
+            "Notes_001.txt",
+            "chapter-1.md",
+            "setup_000.exe",
+            "setup.exe",
+        ],
+    );
+
+    // The same goes when planning renames
+    run_unf(
+        ".",
+        &["-rf", "--transactional", "--normalize-markers", "dir"],
+        "",
+        "rename 'dir/setup-1.exe' -> 'dir/setup_000.exe'\n",
+        "",
+        [
+            "dir",
+            "dir/chapter-1.md",
+            "dir/setup-1.exe",
+            "dir/setup.exe",
+        ],
+        [
+            "dir",
+            "dir/chapter-1.md",
+            "dir/setup_000.exe",
+            "dir/setup.exe",
+        ],
+    );
+}
+
+#[test]
+fn ext_test() {
+    run_unf(
+        ".",
+        &[
+            "-f",
+            "--normalize-ext",
+            "--ext-map",
+            "markdown=md",
+            "A.JPEG",
+            "IMG.JPG",
+            "IMG.jpg",
+            "b.Tif",
+            "c d.htm",
+            "e.markdown",
+            ".Xdefaults",
+        ],
+        "",
+        concat!(
+            "rename 'A.JPEG' -> 'A.jpg'\n",
+            "rename 'IMG.JPG' -> 'IMG_000.jpg'\n",
+            "rename 'b.Tif' -> 'b.tif'\n",
+            "rename 'c d.htm' -> 'c_d.html'\n",
+            "rename 'e.markdown' -> 'e.md'\n",
+        ),
+        "",
+        [
+            "A.JPEG",
+            "IMG.JPG",
+            "IMG.jpg",
+            "b.Tif",
+            "c d.htm",
+            "e.markdown",
+            ".Xdefaults",
+        ],
+        [
+            "A.jpg",
+            "IMG_000.jpg",
+            "IMG.jpg",
+            "b.tif",
+            "c_d.html",
+            "e.md",
+            ".Xdefaults",
+        ],
+    );
+
+    // Directories have no extension
+    run_unf(
+        ".",
+        &["-rf", "--lowercase-ext", "Photos 2020.JPG"],
+        "",
+        concat!(
+            "rename 'Photos 2020.JPG/B.TXT' -> 'Photos 2020.JPG/B.txt'\n",
+            "rename 'Photos 2020.JPG' -> 'Photos_2020.JPG'\n",
+        ),
+        "",
+        // The directory is created as the parent of the file
+        vec!["Photos 2020.JPG/B.TXT"],
+        vec!["Photos_2020.JPG", "Photos_2020.JPG/B.txt"],
+    );
+}
+
+#[test]
+fn replace_stem_dots_test() {
+    run_unf(
+        ".",
+        &[
+            "-f",
+            "--replace-stem-dots",
+            "my.file.name.txt",
+            "Show.S01E02.720p.mkv",
+            "backup 2021.01.tar.gz",
+            ".config.old",
+        ],
+        "",
+        concat!(
+            "rename 'my.file.name.txt' -> 'my_file_name.txt'\n",
+            "rename 'Show.S01E02.720p.mkv' -> 'Show_S01E02_720p.mkv'\n",
+            "rename 'backup 2021.01.tar.gz' -> 'backup_2021_01.tar.gz'\n",
+        ),
+        "",
+        [
+            "my.file.name.txt",
+            "Show.S01E02.720p.mkv",
+            "backup 2021.01.tar.gz",
+            ".config.old",
+        ],
+        [
+            "my_file_name.txt",
+            "Show_S01E02_720p.mkv",
+            "backup_2021_01.tar.gz",
+            ".config.old",
+        ],
+    );
+
+    // Directories have no extension, so all of their dots are replaced
+    run_unf(
+        ".",
+        &["-rf", "--replace-stem-dots", "Show.S01.720p.WEB"],
+        "",
+        "rename 'Show.S01.720p.WEB' -> 'Show_S01_720p_WEB'\n",
+        "",
+        // The directory is created as the parent of the file
+        vec!["Show.S01.720p.WEB/E01.mkv"],
+        vec!["Show_S01_720p_WEB", "Show_S01_720p_WEB/E01.mkv"],
+    );
+}
+
+#[test]
+fn prompt_test() {
+    run_unf(
+        ".",
+        &["a b.txt", "c d.txt", "x y.txt", "z z.txt"],
+        "e\nnew.txt\ny\nn\na\n",
+        "rename 'z z.txt' -> 'z_z.txt'\n",
+        "",
+        &["a b.txt", "c d.txt", "x y.txt", "z z.txt"],
+        &["new.txt", "c d.txt", "x_y.txt", "z_z.txt"],
+    );
+    run_unf(
+        ".",
+        &["-r", "d", "e e.txt", "f f.txt"],
+        "y\ny\ns\nq\n",
+        "",
+        "",
+        &[
+            "d",
+            "d/a a.txt",
+            "d/b b.txt",
+            "d/c c.txt",
+            "e e.txt",
+            "f f.txt",
+        ],
+        &[
+            "d",
+            "d/a_a.txt",
+            "d/b b.txt",
+            "d/c c.txt",
+            "e e.txt",
+            "f f.txt",
+        ],
+    );
+    run_unf(
+        ".",
+        &["a b.txt", "c d.txt"],
+        "?\nd\n",
+        "",
+        concat!(
+            "y - yes\n",
+            "n - no (default)\n",
+            "a - yes to this and all remaining prompts\n",
+            "d - no to this and all remaining prompts\n",
+            "s - no, and skip the rest of this directory\n",
+            "q - quit, leaving everything else as it is\n",
+            "e - edit the new name\n",
+            "? - print this help\n",
+        ),
+        &["a b.txt", "c d.txt"],
+        &["a b.txt", "c d.txt"],
+    );
+}
+
+#[test]
+#[cfg(all(feature = "watch", target_os = "linux"))]
+fn watch_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    fs::create_dir(root.join("drop")).unwrap();
+    fs::File::create(root.join("drop/old file.txt")).unwrap();
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("unf"))
+        .current_dir(root)
+        .args(["watch", "-r", "--debounce", "100", "drop"])
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    // Both files written in place and directories moved in are renamed
+    fs::write(root.join("drop/new file.txt"), "contents").unwrap();
+    fs::create_dir_all(root.join("elsewhere/My Dir")).unwrap();
+    fs::File::create(root.join("elsewhere/My Dir/a b.txt")).unwrap();
+    fs::rename(root.join("elsewhere/My Dir"), root.join("drop/My Dir")).unwrap();
+
+    let renamed = [
+        root.join("drop/new_file.txt"),
+        root.join("drop/My_Dir/a_b.txt"),
+    ];
+    let start = std::time::Instant::now();
+    while !renamed.iter().all(|path| path.exists()) && start.elapsed().as_secs() < 10 {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(renamed.iter().all(|path| path.exists()));
+    assert_eq!(
+        fs::read_to_string(root.join("drop/new_file.txt")).unwrap(),
+        "contents"
+    );
+    // Entries that existed before are left alone
+    assert!(root.join("drop/old file.txt").exists());
+}
+
+/// Read the member paths of the tar archive `archive`, with the targets of
+/// links
+fn tar_members<R: io::Read>(archive: R) -> Vec<(String, Option<String>)> {
+    tar::Archive::new(archive)
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let target = entry
+                .link_name()
+                .unwrap()
+                .map(|target| target.to_string_lossy().into_owned());
+            (path, target)
+        })
+        .collect()
+}
+
+#[test]
+fn archive_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+
+    // Tar archive compressed with gzip
+    let file = fs::File::create(root.join("in.tar.gz")).unwrap();
+    let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_size(0);
+    builder
+        .append_data(&mut header.clone(), "My Docs/", io::empty())
+        .unwrap();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(5);
+    builder
+        .append_data(&mut header.clone(), "My Docs/a b.txt", &b"hello"[..])
+        .unwrap();
+    builder
+        .append_data(&mut header.clone(), "My Docs/a_b.txt", &b"world"[..])
+        .unwrap();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder
+        .append_link(&mut header.clone(), "My Docs/link", "a b.txt")
+        .unwrap();
+    builder.into_inner().unwrap().finish().unwrap();
+
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["archive", "in.tar.gz", "out.tar.gz"])
+        .assert()
+        .success()
+        .stdout(concat!(
+            "rename 'My Docs/' -> 'My_Docs/'\n",
+            "rename 'My Docs/a b.txt' -> 'My_Docs/a_b_000.txt'\n",
+            "rename 'My Docs/a_b.txt' -> 'My_Docs/a_b.txt'\n",
+            "rename 'My Docs/link' -> 'My_Docs/link'\n",
+        ));
+    let out = fs::read(root.join("out.tar.gz")).unwrap();
+    assert_eq!(
+        tar_members(flate2::read::GzDecoder::new(&out[..])),
+        [
+            ("My_Docs/".to_string(), None),
+            ("My_Docs/a_b_000.txt".to_string(), None),
+            ("My_Docs/a_b.txt".to_string(), None),
+            ("My_Docs/link".to_string(), Some("a_b_000.txt".to_string())),
+        ]
+    );
+
+    // The output is never overwritten
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["archive", "in.tar.gz", "out.tar.gz"])
+        .assert()
+        .failure();
+
+    // Zip archive
+    let file = fs::File::create(root.join("in.zip")).unwrap();
+    let mut writer = zip::ZipWriter::new(file);
+    let options = zip::write::SimpleFileOptions::default();
+    writer.start_file("(1) Photo.JPG", options).unwrap();
+    writer.write_all(b"photo").unwrap();
+    writer.start_file("../evil.txt", options).unwrap();
+    writer.finish().unwrap();
+
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["archive", "in.zip", "out.zip"])
+        .assert()
+        .failure()
+        .stderr("unf: error: unsafe path '../evil.txt' in archive\n");
+    assert!(!root.join("out.zip").exists());
+}
+
+#[test]
+fn extract_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+
+    let file = fs::File::create(root.join("in.tar.gz")).unwrap();
+    let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(5);
+    builder
+        .append_data(&mut header.clone(), "My Docs/a b.txt", &b"hello"[..])
+        .unwrap();
+    builder
+        .append_data(&mut header.clone(), "My Docs/a_b.txt", &b"world"[..])
+        .unwrap();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder
+        .append_link(&mut header.clone(), "My Docs/link", "a b.txt")
+        .unwrap();
+    builder
+        .append_link(&mut header.clone(), "escape", "/tmp")
+        .unwrap();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(5);
+    builder
+        .append_data(&mut header.clone(), "escape/x.txt", &b"owned"[..])
+        .unwrap();
+    builder.into_inner().unwrap().finish().unwrap();
+
+    // Existing entries are never overwritten
+    fs::create_dir(root.join("out")).unwrap();
+    fs::create_dir(root.join("out/My_Docs")).unwrap();
+
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["extract", "in.tar.gz", "-C", "out"])
+        .assert()
+        .failure()
+        .stdout(concat!(
+            "rename 'My Docs/a b.txt' -> 'My_Docs_000/a_b.txt'\n",
+            "rename 'My Docs/a_b.txt' -> 'My_Docs_000/a_b_000.txt'\n",
+            "rename 'My Docs/link' -> 'My_Docs_000/link'\n",
+        ))
+        .stderr("unf: error: cannot extract 'escape/x.txt': 'escape' is not a directory\n");
+
+    let out = root.join("out/My_Docs_000");
+    assert_eq!(fs::read(out.join("a_b.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(out.join("a_b_000.txt")).unwrap(), b"world");
+    assert_eq!(
+        fs::read_link(out.join("link")).unwrap(),
+        Path::new("a_b.txt")
+    );
+    assert_eq!(fs::read_dir(root.join("out/My_Docs")).unwrap().count(), 0);
+
+    // Special permission bits of zip members are dropped like for tar
+    // members. The zip writer drops them too, so they are set in the central
+    // directory by hand.
+    let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default().unix_permissions(0o755);
+    writer.start_file("run me", options).unwrap();
+    let mut zip = writer.finish().unwrap().into_inner();
+    let header = zip
+        .windows(4)
+        .position(|window| window == b"PK\x01\x02")
+        .unwrap();
+    zip[header + 38 + 3] |= 0o4000u32.to_le_bytes()[1];
+    fs::write(root.join("in.zip"), zip).unwrap();
+
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["extract", "in.zip", "-C", "out"])
+        .assert()
+        .success()
+        .stdout("rename 'run me' -> 'run_me'\n");
+    let mode = fs::metadata(root.join("out/run_me"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o755);
+}
+
+#[test]
+fn tar_filter_test() {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(0);
+    for path in ["a b.txt", "a_b.txt", "Dir (1)/x y.txt"] {
+        builder
+            .append_data(&mut header.clone(), path, io::empty())
+            .unwrap();
+    }
+    header.set_entry_type(tar::EntryType::Link);
+    builder
+        .append_link(&mut header.clone(), "Dir (1)/hard link", "a b.txt")
+        .unwrap();
+    let input = builder.into_inner().unwrap();
+
+    let output = assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .arg("tar-filter")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stderr("")
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(
+        tar_members(&output[..]),
+        [
+            ("a_b.txt".to_string(), None),
+            ("a_b_000.txt".to_string(), None),
+            ("Dir_1/x_y.txt".to_string(), None),
+            ("Dir_1/hard_link".to_string(), Some("a_b.txt".to_string())),
+        ]
+    );
+}
+
+#[test]
+fn emit_script_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    fs::create_dir_all(root.join("It's a dir")).unwrap();
+    fs::File::create(root.join("It's a dir/$(rm -rf x).txt")).unwrap();
+    fs::File::create(root.join("It's a dir/rm_-rf_x.txt")).unwrap();
+
+    // Each move is guarded against overwriting its target
+    let mv = |from: &str, to: &str| {
+        format!(
+            concat!(
+                "if [ -e {to} ] || [ -L {to} ]; ",
+                "then printf 'target already exists: %s\\n' {to} >&2; exit 1; fi\n",
+                "mv -- {from} {to}\n",
+            ),
+            from = from,
+            to = to
+        )
+    };
+    let script = assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["-r", "--emit-script", "sh", "--undo-script", "undo.sh"])
+        .arg("It's a dir")
+        .assert()
+        .success()
+        .stdout(
+            [
+                "#!/bin/sh\nset -e\n".to_string(),
+                mv(
+                    r"'It'\''s a dir/$(rm -rf x).txt'",
+                    r"'It'\''s a dir/rm_-rf_x_000.txt'",
+                ),
+                mv(r"'It'\''s a dir'", "'It_s_a_dir'"),
+            ]
+            .concat(),
+        )
+        .get_output()
+        .stdout
+        .clone();
+    let undo = fs::read(root.join("undo.sh")).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&undo),
+        [
+            "#!/bin/sh\nset -e\n".to_string(),
+            mv("'It_s_a_dir'", r"'It'\''s a dir'"),
+            mv(
+                r"'It'\''s a dir/rm_-rf_x_000.txt'",
+                r"'It'\''s a dir/$(rm -rf x).txt'",
+            ),
+        ]
+        .concat()
+    );
+
+    // Nothing is renamed until the script is run, and the undo script
+    // restores the original names
+    let before = ["It's a dir/$(rm -rf x).txt", "It's a dir/rm_-rf_x.txt"];
+    let after = ["It_s_a_dir/rm_-rf_x_000.txt", "It_s_a_dir/rm_-rf_x.txt"];
+    let sh = |script: &[u8], paths: &[&str]| {
+        assert_cmd::Command::new("sh")
+            .current_dir(root)
+            .write_stdin(script)
+            .assert()
+            .success();
+        for path in paths {
+            assert!(root.join(path).exists(), "missing {}", path);
+        }
+    };
+    sh(b"", &before);
+    sh(&script, &after);
+    sh(&undo, &before);
+
+    // An existing undo script is never replaced
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["-r", "--emit-script", "sh", "--undo-script", "undo.sh"])
+        .arg("It's a dir")
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr("unf: error: cannot create 'undo.sh': File exists (os error 17)\n");
+    assert_eq!(fs::read(root.join("undo.sh")).unwrap(), undo);
+}
+
+#[test]
+fn apply_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    for dir in ["staging", "replica", "bad"] {
+        fs::create_dir_all(root.join(dir).join("My Dir")).unwrap();
+        fs::File::create(root.join(dir).join("My Dir/a b.txt")).unwrap();
+    }
+    fs::File::create(root.join("bad/My_Dir")).unwrap();
+
+    let mapping = assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root.join("staging"))
+        .args(["-r", "--emit-script", "json", "My Dir"])
+        .assert()
+        .success()
+        .stdout(concat!(
+            "[\n",
+            "  {\n",
+            "    \"from\": \"My Dir/a b.txt\",\n",
+            "    \"to\": \"My Dir/a_b.txt\"\n",
+            "  },\n",
+            "  {\n",
+            "    \"from\": \"My Dir\",\n",
+            "    \"to\": \"My_Dir\"\n",
+            "  }\n",
+            "]\n",
+        ))
+        .get_output()
+        .stdout
+        .clone();
+    fs::write(root.join("mapping.json"), mapping).unwrap();
+
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["apply", "mapping.json", "--root", "replica"])
+        .assert()
+        .success()
+        .stdout(concat!(
+            "rename 'My Dir/a b.txt' -> 'My Dir/a_b.txt'\n",
+            "rename 'My Dir' -> 'My_Dir'\n",
+        ));
+    assert!(root.join("replica/My_Dir/a_b.txt").exists());
+
+    // Nothing is renamed if any rename cannot be done
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["apply", "mapping.json", "--root", "bad"])
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr("unf: error: cannot rename 'My Dir' -> 'My_Dir': target already exists\n");
+    assert!(root.join("bad/My Dir/a b.txt").exists());
+
+    // Mappings that could leave `--root` are refused
+    let abs = root.join("staging/My Dir");
+    let mapping = assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["--emit-script", "json"])
+        .arg(&abs)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    fs::write(root.join("absolute.json"), mapping).unwrap();
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["apply", "absolute.json", "--root", "bad"])
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(format!(
+            "unf: error: cannot rename '{}' below --root: path is absolute or contains '..'\n",
+            abs.display()
+        ));
+    assert!(root.join("staging/My Dir").exists());
+
+    fs::write(
+        root.join("parent.json"),
+        r#"[{"from": "../staging/My Dir", "to": "../staging/My_Dir"}]"#,
+    )
+    .unwrap();
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["apply", "parent.json", "--root", "bad"])
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(
+            "unf: error: cannot rename '../staging/My Dir' below --root: \
+             path is absolute or contains '..'\n",
+        );
+    assert!(root.join("staging/My Dir").exists());
+}
+
+#[test]
+fn on_duplicate_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    for (name, content) in [
+        ("IMG 1.jpg", "photo"),
+        ("IMG_1.jpg", "photo"),
+        ("IMG 2.jpg", "photo"),
+        ("IMG_2.jpg", "other"),
+    ] {
+        fs::write(root.join(name), content).unwrap();
+    }
+
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["-d", "--on-duplicate", "remove", "IMG 1.jpg", "IMG 2.jpg"])
+        .assert()
+        .success()
+        .stdout(concat!(
+            "would remove duplicate 'IMG 1.jpg' of 'IMG_1.jpg'\n",
+            "would rename 'IMG 2.jpg' -> 'IMG_2_000.jpg'\n",
+        ));
+    assert!(root.join("IMG 1.jpg").exists());
+
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["-f", "--on-duplicate", "remove", "IMG 1.jpg", "IMG 2.jpg"])
+        .assert()
+        .success()
+        .stdout(concat!(
+            "remove duplicate 'IMG 1.jpg' of 'IMG_1.jpg'\n",
+            "rename 'IMG 2.jpg' -> 'IMG_2_000.jpg'\n",
+        ));
+    assert!(!root.join("IMG 1.jpg").exists());
+    assert_eq!(fs::read(root.join("IMG_1.jpg")).unwrap(), b"photo");
+    assert_eq!(fs::read(root.join("IMG_2_000.jpg")).unwrap(), b"photo");
+
+    // Downloads duplicated by a browser are removed, whether or not their
+    // markers are normalized
+    fs::write(root.join("file.pdf"), "pdf").unwrap();
+    fs::write(root.join("file (1).pdf"), "pdf").unwrap();
+    for args in [&["-d"][..], &["-d", "--normalize-markers"]] {
+        assert_cmd::Command::cargo_bin("unf")
+            .unwrap()
+            .current_dir(root)
+            .args(args)
+            .args(["--on-duplicate", "remove", "file (1).pdf"])
+            .assert()
+            .success()
+            .stdout("would remove duplicate 'file (1).pdf' of 'file.pdf'\n");
+    }
+    assert_cmd::Command::cargo_bin("unf")
+        .unwrap()
+        .current_dir(root)
+        .args(["-f", "--on-duplicate", "remove", "file (1).pdf"])
+        .assert()
+        .success()
+        .stdout("remove duplicate 'file (1).pdf' of 'file.pdf'\n");
+    assert!(!root.join("file (1).pdf").exists());
+    assert!(root.join("file.pdf").exists());
+}
+
+#[test]
+fn signal_test() {
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    fs::File::create(root.join("a b.txt")).unwrap();
+    fs::File::create(root.join("c d.txt")).unwrap();
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("unf"))
+        .current_dir(root)
+        .args(["a b.txt", "c d.txt"])
+        .stdin(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Send SIGTERM while the first prompt is waiting, then answer it. The
+    // rename in progress is finished, but nothing else is done.
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    std::process::Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    child.stdin.take().unwrap().write_all(b"y\ny\n").unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(143));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "unf: error: interrupted by SIGTERM after 1 renames; the remaining entries were not processed\n"
+    );
+    assert!(root.join("a_b.txt").exists());
+    assert!(root.join("c d.txt").exists());
+}
+
+#[test]
+fn prompt_interrupt_test() {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    let root = TempDir::new().unwrap();
+    let root = root.path();
+    fs::File::create(root.join("a b.txt")).unwrap();
+
+    // Prompts read from a terminal, where Ctrl-C is read as a key
+    let (mut master, slave) = {
+        let (mut master, mut slave) = (0, 0);
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(result, 0);
+        unsafe { (fs::File::from_raw_fd(master), fs::File::from_raw_fd(slave)) }
+    };
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("unf"))
+        .current_dir(root)
+        .arg("a b.txt")
+        .stdin(slave.try_clone().unwrap())
+        .stdout(slave.try_clone().unwrap())
+        .stderr(slave)
+        .spawn()
+        .unwrap();
+
+    // Wait for the prompt, after which the terminal is in raw mode
+    let mut output = Vec::new();
+    let mut buffer = [0; 1024];
+    while !String::from_utf8_lossy(&output).contains("[y,n") {
+        let n = master.read(&mut buffer).unwrap();
+        assert_ne!(n, 0);
+        output.extend_from_slice(&buffer[..n]);
+    }
+    master.write_all(b"\x03").unwrap();
+
+    // Reading fails once the terminal is closed
+    while let Ok(n @ 1..) = master.read(&mut buffer) {
+        output.extend_from_slice(&buffer[..n]);
+    }
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+    assert!(String::from_utf8_lossy(&output).contains(
+        "unf: error: interrupted by SIGINT after 0 renames; the remaining entries were not processed"
+    ));
+    assert!(root.join("a b.txt").exists());
+}